@@ -0,0 +1,52 @@
+use itertools::Itertools;
+use log::{warn, debug};
+use crate::CONFIG;
+
+//FNV-1a, since a hash kept in Anki or on disk mustn't change between Rust releases the way DefaultHasher's can.
+//Each part ends with 0xff, which is never in UTF-8, so ["ab", "c"] and ["a", "bc"] hash differently
+pub(crate) fn build_stable_hash(parts: &[&str]) -> u64 {
+    parts.iter()
+        .flat_map(|part| part.bytes().chain([0xff]))
+        .fold(0xcbf29ce484222325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+//A hash of everything which affects a response, so editing a row only repeats the requests whose inputs changed.
+//e.g. changing a row's definition means it's built again, but its audio is still read from the cache
+pub(crate) fn build_cache_key(kind: &str, inputs: &[&str]) -> String {
+    let parts = [kind].iter().chain(inputs).copied().collect_vec();
+    format!("{}-{:016x}", kind, build_stable_hash(&parts))
+}
+
+pub(crate) fn read_cache(key: &str) -> Option<Vec<u8>> { //None if there is no cache_dir or nothing cached for the key
+    let cache_dir = CONFIG.get()?.cache_dir.as_ref()?;
+    let cached = std::fs::read(cache_dir.join(key)).ok()?;
+    debug!("Read {} from the cache", key);
+    Some(cached)
+}
+
+pub(crate) fn write_cache(key: &str, bytes: &[u8]) {
+    let Some(cache_dir) = CONFIG.get().and_then(|config| config.cache_dir.as_ref()) else {
+        return;
+    };
+    if let Err(error) = std::fs::create_dir_all(cache_dir).and_then(|_| std::fs::write(cache_dir.join(key), bytes)) {
+        warn!("Unable to write {} to the cache: {}", key, error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::providers::translation::build_translation_cache_key;
+
+    #[test]
+    fn test_build_cache_key() {
+        let audio_key = build_cache_key("tts", &["你好", "zh-TW-YunJheNeural/audio-48khz-192kbitrate-mono-mp3", "zh-TW", ""]);
+        assert_eq!(audio_key, build_cache_key("tts", &["你好", "zh-TW-YunJheNeural/audio-48khz-192kbitrate-mono-mp3", "zh-TW", ""]));
+        //Pinned, since a different key would make every cached response miss and be paid for again
+        assert_eq!(audio_key, "tts-e4a795629d8f4eaf");
+        assert_ne!(audio_key, build_cache_key("tts", &["你好", "zh-TW-HsiaoChenNeural/audio-48khz-192kbitrate-mono-mp3", "zh-TW", ""]));
+        assert_ne!(build_cache_key("tts", &["你好"]), build_cache_key("translation", &["你好"]));
+        assert_ne!(build_cache_key("tts", &["ab", "c"]), build_cache_key("tts", &["a", "bc"]));
+        assert_ne!(build_translation_cache_key("你好"), build_translation_cache_key("您好"));
+    }
+}
@@ -0,0 +1,152 @@
+use std::{collections::HashMap, fs::File, io::Write, path::{Path, PathBuf}};
+use genanki_rs::Model;
+use itertools::Itertools;
+use log::info;
+use serde::{Deserialize, Serialize};
+use crate::{Error, deck::{BuiltNote, CLOZE_MODEL_NAME, SENTENCE_MODEL_NAME, WORD_MODEL_NAME, build_note}, providers::tts::AudioFile};
+
+//Rows are keyed by their fields as json, so they can be written back out exactly as they were read
+pub(crate) fn write_failed_rows(failed_rows_file: &Path, failed_row_keys: &[String]) -> Result<(), Error> {
+    if failed_row_keys.is_empty() {
+        if failed_rows_file.exists() {
+            std::fs::remove_file(failed_rows_file)?;
+        }
+        return Ok(());
+    }
+    let mut failed_rows_writer = csv::WriterBuilder::new().flexible(true).from_path(failed_rows_file)?;
+    for row_key in failed_row_keys {
+        failed_rows_writer.write_record(serde_json::from_str::<Vec<String>>(row_key)?)?;
+    }
+    failed_rows_writer.flush()?;
+    info!("Wrote {} failed rows to {}, run with --retry-failed to try them again", failed_row_keys.len(), failed_rows_file.display());
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct CheckpointEntry {
+    pub(crate) row: String,
+    pub(crate) model_name: String,
+    pub(crate) fields: Vec<String>,
+    pub(crate) media_file_names: Vec<String>,
+    #[serde(default)]
+    pub(crate) tags: Vec<String>,
+}
+
+//Records every completed row so an interrupted run can pick up where it left off.
+//Rows are keyed on their full content, so editing a row means it will be processed again.
+pub(crate) struct Checkpoint {
+    dir: PathBuf,
+    completed: HashMap<String, CheckpointEntry>,
+    file: std::sync::Mutex<File>,
+}
+
+impl Checkpoint {
+    pub(crate) fn load(dir: &Path) -> Checkpoint {
+        std::fs::create_dir_all(dir).unwrap();
+        let checkpoint_path = dir.join("checkpoint.jsonl");
+        let completed = Checkpoint::read_completed(dir);
+        if !completed.is_empty() {
+            info!("Resuming from checkpoint, {} rows already completed", completed.len());
+        }
+        let file = std::fs::OpenOptions::new().create(true).append(true).open(&checkpoint_path).unwrap();
+        Checkpoint { dir: dir.to_owned(), completed, file: std::sync::Mutex::new(file) }
+    }
+
+    pub(crate) fn read_completed(dir: &Path) -> HashMap<String, CheckpointEntry> { //Without creating the checkpoint, for looking without resuming
+        match std::fs::read_to_string(dir.join("checkpoint.jsonl")) {
+            Ok(checkpoint) => checkpoint.lines()
+                .filter_map(|line| serde_json::from_str::<CheckpointEntry>(line).ok())
+                .map(|entry| (entry.row.clone(), entry))
+                .collect(),
+            Err(..) => HashMap::new(),
+        }
+    }
+
+    pub(crate) fn is_completed(&self, row: &str) -> bool {
+        self.completed.contains_key(row)
+    }
+
+    pub(crate) fn restore(&self, row: &str, word_model: &Model, sentence_model: &Model, cloze_model: &Model) -> Option<BuiltNote> {
+        let entry = self.completed.get(row)?;
+        let (model_name, model) = match entry.model_name.as_str() {
+            WORD_MODEL_NAME => (WORD_MODEL_NAME, word_model),
+            CLOZE_MODEL_NAME => (CLOZE_MODEL_NAME, cloze_model),
+            _ => (SENTENCE_MODEL_NAME, sentence_model),
+        };
+        Some(BuiltNote {
+            model_name,
+            note: build_note(model.clone(), model_name, &entry.fields),
+            fields: entry.fields.clone(),
+            media: entry.media_file_names.iter().map(|media_file_name| AudioFile { file: self.dir.join(media_file_name) }).collect_vec(),
+            tags: entry.tags.clone(),
+        })
+    }
+
+    pub(crate) fn record(&self, row: &str, built_note: &BuiltNote) {
+        let media_file_names = built_note.media.iter().map(|audio| {
+            let media_file_name = audio.file.file_name().unwrap().to_str().unwrap().to_string();
+            std::fs::copy(&audio.file, self.dir.join(&media_file_name)).unwrap();
+            media_file_name
+        }).collect_vec();
+        let entry = CheckpointEntry {
+            row: row.to_string(),
+            model_name: built_note.model_name.to_string(),
+            fields: built_note.fields.clone(),
+            media_file_names,
+            tags: built_note.tags.clone(),
+        };
+        let mut file = self.file.lock().unwrap();
+        writeln!(file, "{}", serde_json::to_string(&entry).unwrap()).unwrap();
+    }
+
+    pub(crate) fn clear(self) {
+        drop(self.file);
+        std::fs::remove_dir_all(&self.dir).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::{CardConfig, DeckConfig, ModelConfig, StyleConfig}, deck::init_deck};
+
+    #[test]
+    fn test_checkpoint() {
+        let tempdir = tempfile::Builder::new().prefix("test_checkpoint").tempdir().unwrap();
+        let checkpoint_dir = tempdir.path().join("checkpoint");
+        let (_, _, word_model, sentence_model, cloze_model) = init_deck(&ModelConfig { word_model_id: 1, sentence_model_id: 2, cloze_model_id: 4, deck_id: 3, sentence_deck_id: 5 }, &DeckConfig::default(), &CardConfig::default(), &StyleConfig::default());
+        let audio_path = tempdir.path().join("audio.mp3");
+        std::fs::write(&audio_path, "audio").unwrap();
+        let fields = vec![String::from("1"), String::from("你好"), String::from("Hello"), String::from("[sound:audio.mp3]"), String::from("ㄋㄧˇ ㄏㄠˇ")];
+        let built_note = BuiltNote { model_name: SENTENCE_MODEL_NAME, note: build_note(sentence_model.clone(), SENTENCE_MODEL_NAME, &fields), fields: fields.clone(), media: vec![AudioFile { file: audio_path }], tags: vec![String::from("hsk1")] };
+
+        let checkpoint = Checkpoint::load(&checkpoint_dir);
+        checkpoint.record(r#"["你好","Hello"]"#, &built_note);
+        drop(checkpoint);
+
+        let checkpoint = Checkpoint::load(&checkpoint_dir);
+        assert!(checkpoint.is_completed(r#"["你好","Hello"]"#));
+        assert!(!checkpoint.is_completed(r#"["你好","Hi"]"#));
+        let restored_note = checkpoint.restore(r#"["你好","Hello"]"#, &word_model, &sentence_model, &cloze_model).unwrap();
+        assert_eq!(restored_note.model_name, SENTENCE_MODEL_NAME);
+        assert_eq!(restored_note.fields, fields);
+        assert!(restored_note.media[0].file.exists());
+        assert_eq!(restored_note.tags, vec!["hsk1"]);
+        assert_eq!(restored_note.build_json()["fields"]["Meaning"], "Hello");
+        checkpoint.clear();
+        assert!(!checkpoint_dir.exists());
+    }
+
+    #[test]
+    fn test_write_failed_rows() {
+        let tempdir = tempfile::Builder::new().prefix("test_write_failed_rows").tempdir().unwrap();
+        let failed_rows_file = tempdir.path().join("failed_rows.csv");
+        let row_keys = vec![serde_json::to_string(&vec!["我朋友是個街友*基金會*的員工", "My friend works at a homelessness charity, I think"]).unwrap(), serde_json::to_string(&vec!["基金會"]).unwrap()];
+        write_failed_rows(&failed_rows_file, &row_keys).unwrap();
+        let mut failed_rows_reader = csv::ReaderBuilder::new().flexible(true).has_headers(false).from_path(&failed_rows_file).unwrap();
+        let reread_row_keys = failed_rows_reader.records().map(|row| serde_json::to_string(&row.unwrap().iter().collect_vec()).unwrap()).collect_vec();
+        assert_eq!(reread_row_keys, row_keys);
+        write_failed_rows(&failed_rows_file, &[]).unwrap();
+        assert!(!failed_rows_file.exists());
+    }
+}
@@ -0,0 +1,702 @@
+use std::{collections::{HashSet, hash_map::DefaultHasher}, hash::{Hash, Hasher}, fs::File, path::Path, process::Stdio};
+use genanki_rs::{Field, Model, ModelType, Deck, Template, Note, Package};
+use itertools::Itertools;
+use log::{info, warn, debug, trace};
+use reqwest::Client;
+use serde_json::{Value, json};
+use tokio::io::AsyncWriteExt;
+use crate::{CONFIG, Error, LITERAL_ASTERISK, Token, escape_html, restore_literal_asterisks, config::{AnkiConnectConfig, CardConfig, CardTemplate, DeckConfig, MandarinConfig, ModelConfig, NoteGuidConfig, PostProcessConfig, StyleConfig, TimestampFieldConfig}, providers::tts::AudioFile};
+
+fn build_timestamp_field(hanzi: &str) -> String {
+    match CONFIG.get() {
+        Some(config) => config.timestamp_field.build_field(hanzi),
+        None => TimestampFieldConfig::default().build_field(hanzi),
+    }
+}
+
+//The hanzi field of every note in an Anki export, either a .apkg, a collection file or a text export (one note per line, with tab separated fields)
+pub(crate) fn parse_existing_notes(existing_notes_path: &Path, field_index: usize) -> Result<HashSet<String>, Error> {
+    let notes = match existing_notes_path.extension().and_then(|extension| extension.to_str()) {
+        Some("apkg" | "colpkg") => {
+            let mut archive = zip::ZipArchive::new(File::open(existing_notes_path)?)
+                .map_err(|error| Error::Parse(format!("Unable to read {}: {}", existing_notes_path.display(), error)))?;
+            //Anki 2.1.50 and later export a zstd compressed collection.anki21b, alongside a collection.anki2 which only asks you to update Anki
+            let collection_name = ["collection.anki21b", "collection.anki21", "collection.anki2"].into_iter()
+                .find(|collection_name| archive.file_names().any(|file_name| file_name == *collection_name))
+                .ok_or_else(|| Error::Parse(format!("{} has no collection in it, is it an Anki export?", existing_notes_path.display())))?;
+            let mut collection = archive.by_name(collection_name).unwrap();
+            let mut collection_file = tempfile::NamedTempFile::new()?;
+            match collection_name {
+                "collection.anki21b" => zstd::stream::copy_decode(&mut collection, &mut collection_file)?,
+                _ => {
+                    std::io::copy(&mut collection, &mut collection_file)?;
+                },
+            }
+            read_collection_notes(collection_file.path())?
+        },
+        Some("anki21b") => {
+            let mut collection_file = tempfile::NamedTempFile::new()?;
+            zstd::stream::copy_decode(File::open(existing_notes_path)?, &mut collection_file)?;
+            read_collection_notes(collection_file.path())?
+        },
+        Some("anki2" | "anki21") => read_collection_notes(existing_notes_path)?,
+        _ => read_text_export_notes(&std::fs::read_to_string(existing_notes_path)?),
+    };
+    Ok(notes.iter()
+        .filter_map(|fields| fields.get(field_index))
+        .map(|field| build_comparable_hanzi(field))
+        .filter(|field| !field.is_empty())
+        .collect())
+}
+
+fn read_collection_notes(collection_path: &Path) -> Result<Vec<Vec<String>>, Error> { //Anki separates a note's fields with the unit separator
+    let read_notes = || -> rusqlite::Result<Vec<String>> {
+        let connection = rusqlite::Connection::open(collection_path)?;
+        let mut statement = connection.prepare("SELECT flds FROM notes")?;
+        let notes = statement.query_map([], |row| row.get::<_, String>(0))?.collect();
+        notes
+    };
+    let notes = read_notes().map_err(|error| Error::Parse(format!("Unable to read the notes in {}: {}", collection_path.display(), error)))?;
+    Ok(notes.iter().map(|flds| flds.split('\x1f').map(|field| field.to_string()).collect_vec()).collect_vec())
+}
+
+//Anki's header lines say whether a guid, note type or deck column comes before the note's fields
+fn read_text_export_notes(export: &str) -> Vec<Vec<String>> {
+    let leading_columns = export.lines()
+        .filter(|line| ["#guid column:", "#notetype column:", "#deck column:"].iter().any(|header| line.starts_with(header)))
+        .count();
+    export.lines()
+        .filter(|line| !line.starts_with('#'))
+        .map(|line| line.split('\t').skip(leading_columns).map(|field| field.to_string()).collect_vec())
+        .collect_vec()
+}
+
+//A cloze deletion like {{c1::時尚::hint}} is replaced with its answer
+fn remove_cloze_deletions(text: &str) -> String {
+    let mut plain_text = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("{{c") {
+        let Some((deletion, after_deletion)) = rest[start..].split_once("}}") else {
+            break;
+        };
+        let Some((_, answer)) = deletion.split_once("::") else {
+            break;
+        };
+        plain_text.push_str(&rest[..start]);
+        plain_text.push_str(answer.split("::").next().unwrap_or_default());
+        rest = after_deletion;
+    }
+    plain_text.push_str(rest);
+    plain_text
+}
+
+//Generated notes have the stars replaced with html (or cloze deletions), so all of them are removed before comparing
+pub(crate) fn build_comparable_hanzi(hanzi: &str) -> String {
+    let mut comparable_hanzi = String::new();
+    let mut in_tag = false;
+    for char in remove_cloze_deletions(hanzi).chars() {
+        match char {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            '*' | LITERAL_ASTERISK => {},
+            _ if !in_tag => comparable_hanzi.push(char),
+            _ => {},
+        }
+    }
+    comparable_hanzi.trim().to_string()
+}
+
+pub struct BuiltNote {
+    pub model_name: &'static str,
+    pub note: Note,
+    pub fields: Vec<String>,
+    pub media: Vec<AudioFile>,
+    pub tags: Vec<String>, //Added to the tags from the input
+}
+
+impl BuiltNote {
+    pub(crate) fn field_names(&self) -> &'static [&'static str] {
+        match self.model_name {
+            WORD_MODEL_NAME => WORD_MODEL_FIELDS,
+            _ => SENTENCE_MODEL_FIELDS, //Also used by the cloze model
+        }
+    }
+
+    pub(crate) fn build_json(&self) -> Value {
+        json!({
+            "model": self.model_name,
+            "fields": build_fields_json(self.field_names(), &self.fields),
+        })
+    }
+
+    pub(crate) fn build_anki_connect_note(&self, deck_name: &str, tags: &[String]) -> Value {
+        json!({
+            "deckName": deck_name,
+            "modelName": self.model_name,
+            "fields": build_fields_json(self.field_names(), &self.fields),
+            "tags": tags,
+        })
+    }
+}
+
+pub const WORD_MODEL_NAME: &str = "Mandarin Word";
+pub const WORD_MODEL_FIELDS: &[&str] = &["timestamp", "Hanzi", "Definition", "Audio", "Reading", "Similar Words", "Collocations", "Measure Word", "Components", "IPA", "Example Sentence", "Example Audio", "Source", "Slow Audio", "Toneless Reading"];
+pub const SENTENCE_MODEL_NAME: &str = "Mandarin Sentence";
+pub const CLOZE_MODEL_NAME: &str = "Mandarin Sentence Cloze";
+pub const SENTENCE_MODEL_FIELDS: &[&str] = &["timestamp", "Hanzi", "Meaning", "Audio", "Reading", "Word Gloss", "Source", "Slow Audio"];
+
+pub fn init_deck(model_config: &ModelConfig, deck_config: &DeckConfig, card_config: &CardConfig, style_config: &StyleConfig) -> (Deck, Option<Deck>, Model, Model, Model) {
+    let deck = Deck::new(
+        model_config.deck_id, 
+        &deck_config.build_deck_name(),
+        &deck_config.description
+    );
+    let sentence_deck = deck_config.separate_sentence_deck.then(|| Deck::new(
+        model_config.sentence_deck_id,
+        &deck_config.build_sentence_deck_name(),
+        &deck_config.description
+    ));
+    
+    let similar_words_section = build_similar_words_section(style_config);
+    let word_model = Model::new(
+        model_config.word_model_id, 
+        WORD_MODEL_NAME,
+        WORD_MODEL_FIELDS.iter().map(|field| Field::new(field)).collect_vec(),
+        select_templates(vec![
+            (CardTemplate::Listening, Template::new("Listening")
+                .qfmt(&build_listening_front(style_config))
+                .afmt(&r#"
+                    {{FrontSide}}
+                    <hr id=answer>
+                    {{Hanzi}}<br>{{Reading}}<br>{{Definition}}{{#Slow Audio}}<br>{{Slow Audio}}{{/Slow Audio}}
+                    {{#Measure Word}}<br>{{Measure Word}}{{/Measure Word}}
+                    {{#Components}}<br>{{Components}}{{/Components}}
+                    {{#IPA}}<br>{{IPA}}{{/IPA}}
+                    {{#Collocations}}<br>{{Collocations}}{{/Collocations}}
+                    {{#Example Sentence}}<br>{{Example Sentence}}{{#Example Audio}} {{Example Audio}}{{/Example Audio}}{{/Example Sentence}}
+                    <hr id=answer>
+                    {{Similar Words}}
+                    {{#Source}}<div class="source">{{Source}}</div>{{/Source}}
+                "#.replace("{{Similar Words}}", &similar_words_section))),
+            (CardTemplate::Reading, Template::new("Reading")
+                .qfmt("{{Hanzi}}")
+                .afmt(&r#"
+                    {{FrontSide}}
+                    <hr id=answer>
+                    {{Reading}}<br>{{Definition}}{{#Audio}}<br>{{Audio}}{{/Audio}}{{#Slow Audio}} {{Slow Audio}}{{/Slow Audio}}
+                    {{#Measure Word}}<br>{{Measure Word}}{{/Measure Word}}
+                    {{#Components}}<br>{{Components}}{{/Components}}
+                    {{#IPA}}<br>{{IPA}}{{/IPA}}
+                    {{#Collocations}}<br>{{Collocations}}{{/Collocations}}
+                    {{#Example Sentence}}<br>{{Example Sentence}}{{#Example Audio}} {{Example Audio}}{{/Example Audio}}{{/Example Sentence}}
+                    <hr id=answer>
+                    {{Similar Words}}
+                    {{#Source}}<div class="source">{{Source}}</div>{{/Source}}
+                "#.replace("{{Similar Words}}", &similar_words_section))),
+            (CardTemplate::Production, Template::new("Production")
+                .qfmt("{{Definition}}")
+                .afmt(&r#"
+                    {{FrontSide}}
+                    <hr id=answer>
+                    {{Hanzi}}<br>{{Reading}}{{#Audio}}<br>{{Audio}}{{/Audio}}{{#Slow Audio}} {{Slow Audio}}{{/Slow Audio}}
+                    {{#Measure Word}}<br>{{Measure Word}}{{/Measure Word}}
+                    {{#Components}}<br>{{Components}}{{/Components}}
+                    {{#IPA}}<br>{{IPA}}{{/IPA}}
+                    {{#Collocations}}<br>{{Collocations}}{{/Collocations}}
+                    {{#Example Sentence}}<br>{{Example Sentence}}{{#Example Audio}} {{Example Audio}}{{/Example Audio}}{{/Example Sentence}}
+                    <hr id=answer>
+                    {{Similar Words}}
+                    {{#Source}}<div class="source">{{Source}}</div>{{/Source}}
+                "#.replace("{{Similar Words}}", &similar_words_section))),
+            (CardTemplate::Tones, Template::new("Tones")
+                .qfmt("{{#Toneless Reading}}{{Hanzi}}<br>{{Toneless Reading}}{{/Toneless Reading}}")
+                .afmt(r#"
+                    {{FrontSide}}
+                    <hr id=answer>
+                    {{Reading}}<br>{{Definition}}{{#Audio}}<br>{{Audio}}{{/Audio}}{{#Slow Audio}} {{Slow Audio}}{{/Slow Audio}}
+                "#))
+        ], card_config)).css(build_card_css(style_config));
+    
+    let sentence_model = Model::new(
+        model_config.sentence_model_id,
+        SENTENCE_MODEL_NAME,
+        SENTENCE_MODEL_FIELDS.iter().map(|field| Field::new(field)).collect_vec(),
+        select_templates(vec![
+                (CardTemplate::Listening, Template::new("Listening")
+                    .qfmt(&build_listening_front(style_config))
+                    .afmt(r#"
+                        {{FrontSide}}
+                        <hr id=answer>
+                        {{Hanzi}}<br>{{Reading}}<br>{{Meaning}}{{#Slow Audio}}<br>{{Slow Audio}}{{/Slow Audio}}
+                        {{#Word Gloss}}<hr id=answer>{{Word Gloss}}{{/Word Gloss}}
+                        {{#Source}}<div class="source">{{Source}}</div>{{/Source}}
+                    "#)),
+                (CardTemplate::Reading, Template::new("Reading")
+                    .qfmt("{{Hanzi}}")
+                    .afmt(r#"
+                        {{FrontSide}}
+                        <hr id=answer>
+                        {{Reading}}<br>{{Meaning}}{{#Audio}}<br>{{Audio}}{{/Audio}}{{#Slow Audio}} {{Slow Audio}}{{/Slow Audio}}
+                        {{#Word Gloss}}<hr id=answer>{{Word Gloss}}{{/Word Gloss}}
+                        {{#Source}}<div class="source">{{Source}}</div>{{/Source}}
+                    "#)),
+                (CardTemplate::Production, Template::new("Production")
+                    .qfmt("{{Meaning}}")
+                    .afmt(r#"
+                        {{FrontSide}}
+                        <hr id=answer>
+                        {{Hanzi}}<br>{{Reading}}{{#Audio}}<br>{{Audio}}{{/Audio}}{{#Slow Audio}} {{Slow Audio}}{{/Slow Audio}}
+                        {{#Word Gloss}}<hr id=answer>{{Word Gloss}}{{/Word Gloss}}
+                        {{#Source}}<div class="source">{{Source}}</div>{{/Source}}
+                    "#))
+            ], card_config)).css(build_sentence_css(style_config));
+
+    //Cloze notes share the sentence fields, with the cloze deletions in the Hanzi field
+    let cloze_model = Model::new(
+        model_config.cloze_model_id,
+        CLOZE_MODEL_NAME,
+        SENTENCE_MODEL_FIELDS.iter().map(|field| Field::new(field)).collect_vec(),
+        vec![Template::new("Cloze")
+            .qfmt("{{cloze:Hanzi}}")
+            .afmt(r#"
+                {{cloze:Hanzi}}
+                <hr id=answer>
+                {{Reading}}<br>{{Meaning}}{{#Audio}}<br>{{Audio}}{{/Audio}}{{#Slow Audio}} {{Slow Audio}}{{/Slow Audio}}
+                {{#Word Gloss}}<hr id=answer>{{Word Gloss}}{{/Word Gloss}}
+                {{#Source}}<div class="source">{{Source}}</div>{{/Source}}
+            "#)]).css(format!("
+            {}
+
+            .cloze {{
+                color: var(--highlight-color);
+                font-weight: bold;
+            }}
+        ", build_sentence_css(style_config))).model_type(ModelType::Cloze);
+    (deck, sentence_deck, word_model, sentence_model, cloze_model)
+}
+
+fn build_listening_front(style_config: &StyleConfig) -> String {
+    format!("{{{{#Audio}}}}{}{{{{Audio}}}}{{{{/Audio}}}}", escape_html(&style_config.listen_label))
+}
+
+fn build_similar_words_section(style_config: &StyleConfig) -> String {
+    match style_config.similar_words_label.is_empty() {
+        true => "{{Similar Words}}".to_string(),
+        false => format!("{{{{#Similar Words}}}}<div class=label>{}</div>{{{{Similar Words}}}}{{{{/Similar Words}}}}", escape_html(&style_config.similar_words_label)),
+    }
+}
+
+fn build_sentence_css(style_config: &StyleConfig) -> String {
+    format!("
+        {}
+
+        .starred {{
+            color: var(--highlight-color);
+        }}
+    ", build_card_css(style_config))
+}
+
+//Anki adds the nightMode class to the card (and night_mode to the body on older versions) when dark mode is on
+fn build_card_css(style_config: &StyleConfig) -> String {
+    format!("
+        .card {{
+            --text-color: {};
+            --background-color: {};
+            --highlight-color: {};
+            font-family: arial;
+            font-size: 20px;
+            text-align: center;
+            color: var(--text-color);
+            background-color: var(--background-color);
+        }}
+
+        .card.nightMode, .night_mode .card {{
+            --text-color: {};
+            --background-color: {};
+            --highlight-color: {};
+        }}
+
+        ruby {{
+            ruby-position: over;
+            line-height: 2.2;
+        }}
+
+        rt {{
+            font-size: 0.55em;
+        }}
+
+        .label {{
+            font-weight: bold;
+        }}
+
+        .gloss, .similar, .senses {{
+            margin: auto;
+            text-align: left;
+        }}
+
+        .similar, .senses {{
+            display: inline-block;
+        }}
+
+        .source {{
+            margin-top: 1em;
+            font-size: 0.6em;
+            opacity: 0.6;
+        }}
+    ", style_config.text_color, style_config.background_color, style_config.highlight_color,
+        style_config.night_text_color, style_config.night_background_color, style_config.night_highlight_color)
+}
+
+fn select_templates(templates: Vec<(CardTemplate, Template)>, card_config: &CardConfig) -> Vec<Template> {
+    templates.into_iter()
+        .filter(|(card_template, _)| card_config.templates.contains(card_template))
+        .map(|(_, template)| template)
+        .collect_vec()
+}
+
+//Everything on a word note which isn't derived from the token itself. Anything left as the default is an empty field
+#[derive(Debug, Default)]
+pub struct WordFields<'a> {
+    pub definition: String,
+    pub audio: Option<&'a AudioFile>,
+    pub reading: String,
+    pub similar_words: String,
+    pub measure_word: String,
+    pub components: String,
+    pub ipa: String,
+    pub example_sentence: Option<String>,
+    pub example_audio: Option<&'a AudioFile>,
+    pub definition_source: String,
+    pub slow_audio: Option<&'a AudioFile>,
+    pub toneless_reading: String,
+}
+
+impl WordFields<'_> {
+    pub fn build(self, token: &Token, mandarin_config: &MandarinConfig) -> Vec<String> { //In the order of WORD_MODEL_FIELDS
+        [
+            build_timestamp_field(&token.text),
+            token.text.clone(),
+            self.definition,
+            self.audio.map(|audio| audio.build_note_field()).unwrap_or_default(),
+            self.reading,
+            self.similar_words,
+            token.build_collocations(mandarin_config),
+            self.measure_word,
+            self.components,
+            self.ipa,
+            self.example_sentence.map(|example_sentence| escape_html(&example_sentence)).unwrap_or_default(),
+            self.example_audio.map(|audio| audio.build_note_field()).unwrap_or_default(),
+            self.definition_source,
+            self.slow_audio.map(|audio| audio.build_note_field()).unwrap_or_default(),
+            self.toneless_reading
+        ].iter().map(|field| restore_literal_asterisks(field)).collect_vec()
+    }
+}
+
+pub fn build_sentence_fields(note_sentence: String, definition: String, audio: Option<&AudioFile>, note_reading: String, word_gloss: String, definition_source: String, slow_audio: Option<&AudioFile>) -> Vec<String> {
+    [
+        build_timestamp_field(&note_sentence),
+        note_sentence,
+        definition,
+        audio.map(|audio| audio.build_note_field()).unwrap_or_default(),
+        note_reading,
+        word_gloss,
+        definition_source,
+        slow_audio.map(|audio| audio.build_note_field()).unwrap_or_default()
+    ].iter().map(|field| restore_literal_asterisks(field)).collect_vec()
+}
+
+//A card without one of these fields isn't worth studying, so it is skipped rather than added half empty
+const REQUIRED_FIELDS: &[&str] = &["Hanzi", "Definition", "Meaning", "Reading"];
+
+pub(crate) fn has_required_fields(hanzi: &str, field_names: &[&str], fields: &[String]) -> bool {
+    let empty_fields = field_names.iter().zip(fields.iter())
+        .filter(|(field_name, field)| REQUIRED_FIELDS.contains(field_name) && field.trim().is_empty())
+        .map(|(field_name, _)| *field_name)
+        .collect_vec();
+    if !empty_fields.is_empty() {
+        warn!("Skipping {} as it has no {}", hanzi, empty_fields.join(" or "));
+    }
+    empty_fields.is_empty()
+}
+
+pub(crate) fn build_note(model: Model, model_name: &str, fields: &[String]) -> Note {
+    let note = Note::new(model, fields.iter().map(|field| field.as_str()).collect_vec()).unwrap();
+    match CONFIG.get().map(|config| &config.note_guid) {
+        Some(NoteGuidConfig::Timestamp) => note,
+        _ => note.guid(build_note_guid(model_name, &fields[1], CONFIG.get().and_then(|config| config.guid_namespace.as_deref()))),
+    }
+}
+
+//Hashed the same way genanki hashes fields for its default guid.
+//Without a namespace the guid is the same as before namespaces existed, so earlier imports are still updated
+fn build_note_guid(model_name: &str, hanzi: &str, namespace: Option<&str>) -> String {
+    let mut hasher = DefaultHasher::new();
+    match namespace {
+        Some(namespace) => (namespace, model_name, hanzi).hash(&mut hasher),
+        None => (model_name, hanzi).hash(&mut hasher),
+    }
+    hasher.finish().to_string()
+}
+
+fn build_fields_json(field_names: &[&str], fields: &[String]) -> serde_json::Map<String, Value> {
+    field_names.iter().zip(fields.iter())
+        .map(|(field_name, field)| (field_name.to_string(), json!(field)))
+        .collect()
+}
+
+//Sends {"model": ..., "fields": {field name: value}} to the configured command's stdin and reads the same shape back from its stdout.
+//Any field missing from the response is left unchanged, and if the command fails the original fields are used.
+pub(crate) async fn post_process_fields(model_name: &str, field_names: &[&str], fields: Vec<String>, post_process_config: &PostProcessConfig) -> Vec<String> {
+    let input = json!({
+        "model": model_name,
+        "fields": build_fields_json(field_names, &fields),
+    });
+    let output = run_post_process_command(&input, post_process_config).await;
+    debug!("Post Process Output: {:#?}", output);
+
+    match output {
+        Ok(output) => field_names.iter().zip(fields).map(|(field_name, field)| {
+            match output["fields"][field_name].as_str() {
+                Some(processed_field) => processed_field.to_string(),
+                None => field,
+            }
+        }).collect_vec(),
+        Err(error) => {
+            warn!("Post process command failed, using unprocessed fields: {}", error);
+            fields
+        }
+    }
+}
+
+async fn run_post_process_command(input: &Value, post_process_config: &PostProcessConfig) -> Result<Value, Error> {
+    let mut child = tokio::process::Command::new(&post_process_config.command)
+        .args(&post_process_config.args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(input.to_string().as_bytes()).await?;
+    drop(stdin);
+
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        return Err(Error::Command { command: post_process_config.command.clone(), status: output.status });
+    }
+    Ok(serde_json::from_slice::<Value>(&output.stdout)?)
+}
+
+pub(crate) fn write_package_atomically(package: &mut Package, output_path: &Path) -> Result<(), Error> { //Writes to a temp file alongside the output and renames it, so a failed write never clobbers the previous output
+    let output_dir = match output_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let temp_output = tempfile::Builder::new().prefix(".output").suffix(".apkg").tempfile_in(output_dir)?;
+    package.write(temp_output.as_file()).map_err(|error| Error::Package(Box::new(error)))?;
+    temp_output.as_file().sync_all()?;
+    temp_output.persist(output_path).map_err(|error| error.error)?;
+    Ok(())
+}
+
+fn build_anki_connect_request(action: &str, params: Value, key: Option<&str>) -> Value {
+    let mut request = json!({
+        "action": action,
+        "version": 6,
+        "params": params,
+    });
+    if let Some(key) = key {
+        request["key"] = json!(key);
+    }
+    request
+}
+
+pub(crate) async fn invoke_anki_connect(action: &str, params: Value, client: &Client, anki_connect_config: &AnkiConnectConfig) -> Result<Value, Error> {
+    let request = build_anki_connect_request(action, params, anki_connect_config.key.as_deref());
+    let response: Value = client.post(&anki_connect_config.url).json(&request).send().await?.error_for_status()?.json().await?;
+    trace!("AnkiConnect {} Response: {:#?}", action, response);
+    match response["error"].as_str() {
+        Some(error) => Err(Error::AnkiConnect(format!("{} failed: {}", action, error))),
+        None => Ok(response["result"].clone()),
+    }
+}
+
+//The note types have to exist already, which importing any .apkg from this script does once
+pub async fn add_notes_to_anki(notes: &[Value], media: &[AudioFile], client: &Client, anki_connect_config: &AnkiConnectConfig) -> Result<(), Error> {
+    let model_names = invoke_anki_connect("modelNames", json!({}), client, anki_connect_config).await?;
+    let missing_models = notes.iter()
+        .filter_map(|note| note["modelName"].as_str())
+        .unique()
+        .filter(|model_name| !model_names.as_array().is_some_and(|model_names| model_names.contains(&json!(model_name))))
+        .collect_vec();
+    if !missing_models.is_empty() {
+        return Err(Error::AnkiConnect(format!("Anki doesn't have the note types {}, import an .apkg made by this script once to create them", missing_models.join(", "))));
+    }
+    for deck_name in notes.iter().filter_map(|note| note["deckName"].as_str()).unique() {
+        invoke_anki_connect("createDeck", json!({"deck": deck_name}), client, anki_connect_config).await?;
+    }
+    for audio_file in media {
+        let filename = audio_file.file.file_name().unwrap().to_str().unwrap();
+        invoke_anki_connect("storeMediaFile", json!({"filename": filename, "path": audio_file.file}), client, anki_connect_config).await?;
+    }
+    let added_notes = invoke_anki_connect("addNotes", json!({"notes": notes}), client, anki_connect_config).await?;
+    let added_count = added_notes.as_array().map(|added_notes| added_notes.iter().filter(|note_id| !note_id.is_null()).count()).unwrap_or_default();
+    info!("Added {} notes to Anki", added_count);
+    if added_count < notes.len() {
+        warn!("{} notes weren't added, Anki may already have them", notes.len() - added_count);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use crate::{tokenise_sentence, config::GenankiConfig};
+
+    #[test]
+    fn test_write_package_atomically() {
+        let tempdir = tempfile::Builder::new().prefix("test_write_package_atomically").tempdir().unwrap();
+        let output_path = tempdir.path().join("output.apkg");
+        std::fs::write(&output_path, "previous output").unwrap();
+        let (deck, _, _, _, _) = init_deck(&ModelConfig { word_model_id: 1, sentence_model_id: 2, cloze_model_id: 4, deck_id: 3, sentence_deck_id: 5 }, &DeckConfig::default(), &CardConfig::default(), &StyleConfig::default());
+        let mut package = Package::new(vec![deck], vec![]).unwrap();
+        write_package_atomically(&mut package, &output_path).unwrap();
+        assert_ne!(std::fs::read(&output_path).unwrap(), b"previous output");
+        assert_eq!(std::fs::read_dir(tempdir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_build_card_css() {
+        let style_config = serde_json::from_value::<StyleConfig>(json!({"night_background_color": "black"})).unwrap();
+        let css = build_card_css(&style_config);
+        assert!(css.contains("--background-color: white;"));
+        assert!(css.contains("--background-color: black;"));
+        assert!(css.contains(".card.nightMode"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_post_process_fields() {
+        let fields = vec![String::from("1"), String::from("你好"), String::from("Hello")];
+        let post_process_config = PostProcessConfig { command: String::from("sh"), args: vec![String::from("-c"), String::from(r#"cat > /dev/null; echo '{"fields": {"Meaning": "Hi"}}'"#)] };
+        let processed_fields = post_process_fields(SENTENCE_MODEL_NAME, SENTENCE_MODEL_FIELDS, fields.clone(), &post_process_config).await;
+        assert_eq!(processed_fields, vec!["1", "你好", "Hi"]);
+        let post_process_config = PostProcessConfig { command: String::from("false"), args: vec![] };
+        let processed_fields = post_process_fields(SENTENCE_MODEL_NAME, SENTENCE_MODEL_FIELDS, fields.clone(), &post_process_config).await;
+        assert_eq!(processed_fields, fields);
+    }
+
+    #[test]
+    fn test_build_note_guid() {
+        assert_eq!(build_note_guid(WORD_MODEL_NAME, "你好", None), build_note_guid(WORD_MODEL_NAME, "你好", None));
+        assert_ne!(build_note_guid(WORD_MODEL_NAME, "你好", None), build_note_guid(SENTENCE_MODEL_NAME, "你好", None));
+        assert_ne!(build_note_guid(WORD_MODEL_NAME, "你好", None), build_note_guid(WORD_MODEL_NAME, "您好", None));
+        //The same word in two projects' decks
+        assert_eq!(build_note_guid(WORD_MODEL_NAME, "你好", Some("chapter3")), build_note_guid(WORD_MODEL_NAME, "你好", Some("chapter3")));
+        assert_ne!(build_note_guid(WORD_MODEL_NAME, "你好", Some("chapter3")), build_note_guid(WORD_MODEL_NAME, "你好", Some("travel")));
+        assert_ne!(build_note_guid(WORD_MODEL_NAME, "你好", Some("chapter3")), build_note_guid(WORD_MODEL_NAME, "你好", None));
+    }
+
+    #[test]
+    fn test_parse_existing_notes() {
+        let mut existing_notes_file = tempfile::Builder::new().suffix(".txt").tempfile().unwrap();
+        writeln!(existing_notes_file, "#separator:tab\n1694000000\t時尚\tfashion\n1694000001\t你今天看起來很<span class=starred>時尚</span>\tYou look stylish today").unwrap();
+        let existing_hanzi = parse_existing_notes(existing_notes_file.path(), 1).unwrap();
+        assert!(existing_hanzi.contains(&build_comparable_hanzi("時尚")));
+        assert!(existing_hanzi.contains(&build_comparable_hanzi("你今天看起來很*時尚*")));
+        assert!(!existing_hanzi.contains(&build_comparable_hanzi("寵")));
+        assert!(!existing_hanzi.contains("#separator:tab"));
+        //Only the hanzi field is compared, so a row matching another field isn't skipped
+        assert!(!existing_hanzi.contains("fashion"));
+
+        let mut existing_notes_file = tempfile::Builder::new().suffix(".txt").tempfile().unwrap();
+        writeln!(existing_notes_file, "#separator:tab\n#guid column:1\n#notetype column:2\nguid\tMandarin Sentence Cloze\t1694000000\t你今天看起來很{{{{c1::時尚::fashionable}}}}\tYou look stylish today").unwrap();
+        let existing_hanzi = parse_existing_notes(existing_notes_file.path(), 1).unwrap();
+        assert_eq!(existing_hanzi, HashSet::from([String::from("你今天看起來很時尚")]));
+    }
+
+    #[test]
+    fn test_parse_existing_notes_apkg() {
+        let tempdir = tempfile::Builder::new().prefix("test_parse_existing_notes_apkg").tempdir().unwrap();
+        let apkg_path = tempdir.path().join("existing.apkg");
+        let (mut deck, _, word_model, _, _) = init_deck(&ModelConfig { word_model_id: 1, sentence_model_id: 2, cloze_model_id: 4, deck_id: 3, sentence_deck_id: 5 }, &DeckConfig::default(), &CardConfig::default(), &StyleConfig::default());
+        let fields = WORD_MODEL_FIELDS.iter().map(|field| match *field {
+            "Hanzi" => String::from("時尚"),
+            _ => String::from("1"),
+        }).collect_vec();
+        deck.add_note(build_note(word_model, WORD_MODEL_NAME, &fields));
+        Package::new(vec![deck], vec![]).unwrap().write_to_file(apkg_path.to_str().unwrap()).unwrap();
+        assert_eq!(parse_existing_notes(&apkg_path, 1).unwrap(), HashSet::from([String::from("時尚")]));
+
+        //A newer Anki's export, with the real collection compressed and a collection.anki2 which can't be read
+        let mut archive = zip::ZipArchive::new(File::open(&apkg_path).unwrap()).unwrap();
+        let mut collection = Vec::new();
+        std::io::Read::read_to_end(&mut archive.by_name("collection.anki2").unwrap(), &mut collection).unwrap();
+        let modern_apkg_path = tempdir.path().join("modern.apkg");
+        let mut zip_writer = zip::ZipWriter::new(File::create(&modern_apkg_path).unwrap());
+        for (name, contents) in [("collection.anki2", b"Please update to the latest Anki version".to_vec()), ("collection.anki21b", zstd::encode_all(collection.as_slice(), 0).unwrap())] {
+            zip_writer.start_file(name, zip::write::FileOptions::default()).unwrap();
+            zip_writer.write_all(&contents).unwrap();
+        }
+        zip_writer.finish().unwrap();
+        assert_eq!(parse_existing_notes(&modern_apkg_path, 1).unwrap(), HashSet::from([String::from("時尚")]));
+        assert!(parse_existing_notes(&tempdir.path().join("missing.apkg"), 1).is_err());
+    }
+
+    #[test]
+    fn test_build_timestamp_field() {
+        assert_eq!(TimestampFieldConfig::ContentHash.build_field("時尚"), TimestampFieldConfig::ContentHash.build_field("時尚"));
+        assert_ne!(TimestampFieldConfig::ContentHash.build_field("時尚"), TimestampFieldConfig::ContentHash.build_field("基金會"));
+        //Pinned, since changing it would stop Anki flagging words imported before as duplicates
+        assert_eq!(TimestampFieldConfig::ContentHash.build_field("時尚"), "cb760b491b19d9b4");
+        assert_ne!(TimestampFieldConfig::EpochNanos.build_field("時尚"), TimestampFieldConfig::EpochNanos.build_field("時尚"));
+        let iso_timestamp = TimestampFieldConfig::Iso8601.build_field("時尚");
+        assert!(iso_timestamp.contains('T') && iso_timestamp.ends_with('Z'));
+    }
+
+    #[test]
+    fn test_for_testing_config() {
+        let config = GenankiConfig::for_testing();
+        assert!(config.cards.audio && config.cards.similar_words);
+        assert!(config.openai.is_none());
+        let token = &tokenise_sentence("時尚")[0];
+        let word_fields = WordFields { definition: String::from("fashion"), reading: token.build_reading(&config.mandarin.reading).unwrap(), ..WordFields::default() }.build(token, &config.mandarin);
+        assert_eq!(word_fields.len(), WORD_MODEL_FIELDS.len());
+        assert_eq!(word_fields[4], "ㄕˊ,ㄕㄤˋ");
+    }
+
+    #[test]
+    fn test_has_required_fields() {
+        let fields = ["1", "你好", "Hello", "", "ㄋㄧˇ ㄏㄠˇ"].map(String::from);
+        assert!(has_required_fields("你好", SENTENCE_MODEL_FIELDS, &fields));
+        let fields = ["1", "你好", "Hello", "[sound:你好.mp3]", " "].map(String::from);
+        assert!(!has_required_fields("你好", SENTENCE_MODEL_FIELDS, &fields));
+    }
+
+    #[test]
+    fn test_card_labels() {
+        let mut style_config = StyleConfig::default();
+        assert_eq!(build_listening_front(&style_config), "{{#Audio}}Listen.{{Audio}}{{/Audio}}");
+        assert_eq!(build_similar_words_section(&style_config), "{{Similar Words}}");
+
+        style_config.listen_label = "Hör zu.".to_string();
+        style_config.similar_words_label = "Ähnliche Wörter".to_string();
+        assert_eq!(build_listening_front(&style_config), "{{#Audio}}Hör zu.{{Audio}}{{/Audio}}");
+        assert_eq!(build_similar_words_section(&style_config), "{{#Similar Words}}<div class=label>Ähnliche Wörter</div>{{Similar Words}}{{/Similar Words}}");
+    }
+
+    #[test]
+    fn test_build_anki_connect_note() {
+        let (_, _, _, sentence_model, _) = init_deck(&ModelConfig { word_model_id: 1, sentence_model_id: 2, cloze_model_id: 3, deck_id: 4, sentence_deck_id: 5 }, &DeckConfig::default(), &CardConfig::default(), &StyleConfig::default());
+        let fields = SENTENCE_MODEL_FIELDS.iter().map(|field| field.to_string()).collect_vec();
+        let built_note = BuiltNote { model_name: SENTENCE_MODEL_NAME, note: build_note(sentence_model, SENTENCE_MODEL_NAME, &fields), fields: fields.clone(), media: Vec::new(), tags: Vec::new() };
+        let note = built_note.build_anki_connect_note("Mandarin::Chapter3", &[String::from("chapter3")]);
+        assert_eq!(note["deckName"], "Mandarin::Chapter3");
+        assert_eq!(note["modelName"], SENTENCE_MODEL_NAME);
+        assert_eq!(note["fields"]["Meaning"], "Meaning");
+        assert_eq!(note["tags"], json!(["chapter3"]));
+        let request = build_anki_connect_request("addNotes", json!({"notes": [note]}), Some("secret"));
+        assert_eq!(request["version"], 6);
+        assert_eq!(request["key"], "secret");
+        assert!(build_anki_connect_request("modelNames", json!({}), None).get("key").is_none());
+    }
+}
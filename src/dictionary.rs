@@ -0,0 +1,324 @@
+use std::{collections::HashMap, fs::File, path::Path};
+use chinese_dictionary::{WordEntry, MeasureWord};
+use itertools::Itertools;
+use log::info;
+use pinyin_zhuyin::encode_zhuyin;
+use serde_json::Value;
+use tokio::sync::OnceCell;
+use crate::{CONFIG, Error, config::{CedictConfig, CedictMode, DictionaryDefinitionConfig, PinyinSource}, pinyin::convert_pinyin_numbers_to_marks};
+
+pub(crate) static GLOSSARY: OnceCell<HashMap<String, String>> = OnceCell::const_new();
+pub(crate) static COMPONENTS: OnceCell<HashMap<String, String>> = OnceCell::const_new();
+pub(crate) static CEDICT: OnceCell<CedictDictionary> = OnceCell::const_new();
+pub(crate) fn build_dictionary_definition(word_entries: &[&WordEntry]) -> String {
+    match CONFIG.get() {
+        Some(config) => config.dictionary_definition.build_definition(word_entries),
+        None => DictionaryDefinitionConfig::default().build_definition(word_entries),
+    }
+}
+
+pub(crate) fn parse_glossary(glossary_path: &Path) -> Result<HashMap<String, String>, Error> { //Either a json object or a two column csv of hanzi to definition
+    match glossary_path.extension().and_then(|extension| extension.to_str()) {
+        Some("json") => Ok(serde_json::from_reader(File::open(glossary_path)?)?),
+        _ => {
+            let mut glossary_reader = csv::ReaderBuilder::new()
+                .flexible(true)
+                .has_headers(false)
+                .trim(csv::Trim::All)
+                .from_path(glossary_path)?;
+            let mut glossary = HashMap::new();
+            for row in glossary_reader.records() {
+                let row = row?;
+                if row.len() >= 2 {
+                    glossary.insert(row[0].to_string(), row[1].to_string());
+                }
+            }
+            Ok(glossary)
+        }
+    }
+}
+
+pub(crate) fn lookup_glossary(hanzi: &str) -> Option<String> {
+    GLOSSARY.get().and_then(|glossary| glossary.get(hanzi)).cloned()
+}
+
+//Make Me a Hanzi's dictionary.txt, one json object per line with the character, its decomposition and its radical
+pub(crate) fn parse_components(components_path: &Path) -> Result<HashMap<String, String>, Error> {
+    Ok(std::fs::read_to_string(components_path)?.lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .filter_map(|entry| {
+            let character = entry["character"].as_str()?;
+            let decomposition = entry["decomposition"].as_str().filter(|decomposition| !decomposition.contains('？'))?;
+            let components = match entry["radical"].as_str() {
+                Some(radical) => format!("{} (radical {})", decomposition, radical),
+                None => decomposition.to_string(),
+            };
+            Some((character.to_string(), components))
+        })
+        .collect())
+}
+
+pub(crate) fn lookup_components(hanzi: &str) -> String { //Only single characters are decomposed
+    match hanzi.chars().count() {
+        1 => COMPONENTS.get().and_then(|components| components.get(hanzi)).cloned().unwrap_or_default(),
+        _ => String::new(),
+    }
+}
+
+pub(crate) trait DeriveZhuyin {
+    fn derive_zhuyin(&self) -> String;
+}
+
+impl DeriveZhuyin for WordEntry {
+    fn derive_zhuyin(&self) -> String {
+        self.pinyin_numbers.split_whitespace()
+            .map(|pinyin| encode_zhuyin(pinyin).unwrap_or(pinyin.to_string()))
+            .join(",")
+    }
+}
+
+impl DeriveZhuyin for MeasureWord {
+    fn derive_zhuyin(&self) -> String {
+        self.pinyin_numbers.split_whitespace()
+            .map(|pinyin| encode_zhuyin(pinyin).unwrap_or(pinyin.to_string()))
+            .join(",")
+    }
+}
+
+//A single syllable of a dictionary word, so ruby annotations are read the same way as whole words
+pub(crate) struct Syllable<'a> {
+    pub(crate) pinyin_numbers: &'a str,
+    pub(crate) pinyin_marks: &'a str,
+}
+
+impl DeriveZhuyin for Syllable<'_> {
+    fn derive_zhuyin(&self) -> String {
+        encode_zhuyin(self.pinyin_numbers).unwrap_or(self.pinyin_numbers.to_string())
+    }
+}
+
+pub(crate) trait DictionaryReading: DeriveZhuyin {
+    fn pinyin_numbers(&self) -> &str;
+    fn pinyin_marks(&self) -> &str;
+    fn build_pinyin(&self) -> String {
+        match CONFIG.get().map(|config| config.mandarin.pinyin_source).unwrap_or_default() {
+            PinyinSource::Dictionary => self.pinyin_marks().to_string(),
+            PinyinSource::Numbers => derive_pinyin_marks(self.pinyin_numbers()),
+        }
+    }
+}
+
+fn derive_pinyin_marks(pinyin_numbers: &str) -> String {
+    pinyin_numbers.replace("u:", "ü").split_whitespace().map(convert_pinyin_numbers_to_marks).join(" ")
+}
+
+impl DictionaryReading for WordEntry {
+    fn pinyin_numbers(&self) -> &str {
+        &self.pinyin_numbers
+    }
+    fn pinyin_marks(&self) -> &str {
+        &self.pinyin_marks
+    }
+}
+
+impl DictionaryReading for MeasureWord {
+    fn pinyin_numbers(&self) -> &str {
+        &self.pinyin_numbers
+    }
+    fn pinyin_marks(&self) -> &str {
+        &self.pinyin_marks
+    }
+}
+
+impl DictionaryReading for Syllable<'_> {
+    fn pinyin_numbers(&self) -> &str {
+        self.pinyin_numbers
+    }
+    fn pinyin_marks(&self) -> &str {
+        self.pinyin_marks
+    }
+}
+
+trait DictionaryLookup {
+    fn query_by_chinese(&self, text: &str) -> Vec<&'static WordEntry>;
+}
+
+struct BundledDictionary;
+
+impl DictionaryLookup for BundledDictionary {
+    fn query_by_chinese(&self, text: &str) -> Vec<&'static WordEntry> {
+        chinese_dictionary::query_by_chinese(text)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct CedictDictionary {
+    entries: HashMap<&'static str, Vec<&'static WordEntry>>,
+    mode: CedictMode,
+}
+
+impl CedictDictionary {
+    pub(crate) fn load(cedict_config: &CedictConfig) -> CedictDictionary {
+        let cedict = std::fs::read_to_string(&cedict_config.path).unwrap();
+        CedictDictionary::parse(&cedict, cedict_config.mode)
+    }
+
+    fn parse(cedict: &str, mode: CedictMode) -> CedictDictionary {
+        //The dictionary lives for the rest of the run, so leak the entries to hand out the same &'static refs as chinese_dictionary
+        let word_entries: &'static [WordEntry] = Box::leak(cedict.lines().filter_map(parse_cedict_line).collect_vec().into_boxed_slice());
+        let mut entries: HashMap<&'static str, Vec<&'static WordEntry>> = HashMap::new();
+        for word_entry in word_entries {
+            entries.entry(word_entry.traditional.as_str()).or_default().push(word_entry);
+            if word_entry.simplified != word_entry.traditional {
+                entries.entry(word_entry.simplified.as_str()).or_default().push(word_entry);
+            }
+        }
+        info!("Loaded {} CC-CEDICT entries", word_entries.len());
+        CedictDictionary { entries, mode }
+    }
+}
+
+impl DictionaryLookup for CedictDictionary {
+    fn query_by_chinese(&self, text: &str) -> Vec<&'static WordEntry> {
+        let cedict_entries = self.entries.get(text).cloned().unwrap_or_default();
+        match (&self.mode, cedict_entries.is_empty()) {
+            (CedictMode::Prefer, false) => cedict_entries,
+            (CedictMode::Merge, false) => cedict_entries.into_iter().chain(BundledDictionary.query_by_chinese(text)).collect_vec(),
+            (_, true) => BundledDictionary.query_by_chinese(text),
+        }
+    }
+}
+
+pub(crate) fn query_by_chinese(text: &str) -> Vec<&'static WordEntry> {
+    match CEDICT.get() {
+        Some(cedict) => cedict.query_by_chinese(text),
+        None => BundledDictionary.query_by_chinese(text),
+    }
+}
+
+fn parse_cedict_line(line: &str) -> Option<WordEntry> { //e.g. 傳統 传统 [chuan2 tong3] /tradition/traditional/convention/
+    if line.starts_with('#') {
+        return None;
+    }
+    let (traditional, rest) = line.split_once(' ')?;
+    let (simplified, rest) = rest.split_once(" [")?;
+    let (pinyin_numbers, rest) = rest.split_once("] /")?;
+    let pinyin_numbers = pinyin_numbers.replace("u:", "ü");
+    let (measure_words, english): (Vec<&str>, Vec<&str>) = rest.trim_end().trim_end_matches('/').split('/')
+        .partition(|definition| definition.starts_with("CL:"));
+    Some(WordEntry {
+        traditional: traditional.to_string(),
+        simplified: simplified.to_string(),
+        pinyin_marks: pinyin_numbers.split_whitespace().map(convert_pinyin_numbers_to_marks).join(" "),
+        tone_marks: pinyin_numbers.split_whitespace().filter_map(|syllable| syllable.chars().last()?.to_digit(10).map(|tone| tone as u8)).collect_vec(),
+        pinyin_numbers,
+        english: english.into_iter().map(|definition| definition.to_string()).collect_vec(),
+        hash: 0,
+        measure_words: measure_words.into_iter()
+            .flat_map(|measure_words| measure_words.trim_start_matches("CL:").split(','))
+            .filter_map(parse_cedict_measure_word)
+            .collect_vec(),
+        hsk: 0,
+        word_id: 0,
+    })
+}
+
+fn parse_cedict_measure_word(measure_word: &str) -> Option<MeasureWord> { //e.g. 個|个[ge4], or 個[ge4] when both scripts are the same
+    let (hanzi, pinyin_numbers) = measure_word.trim_end_matches(']').split_once('[')?;
+    let (traditional, simplified) = hanzi.split_once('|').unwrap_or((hanzi, hanzi));
+    Some(MeasureWord {
+        traditional: traditional.to_string(),
+        simplified: simplified.to_string(),
+        pinyin_marks: pinyin_numbers.split_whitespace().map(convert_pinyin_numbers_to_marks).join(" "),
+        pinyin_numbers: pinyin_numbers.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DefinitionField;
+
+    #[test]
+    fn test_derive_zhuyin() {
+        let word = query_by_chinese("刮目");
+        println!("Parsed Word: {:#?}", word);
+        println!("Generated Sentence: {:#?}", word[0].derive_zhuyin());
+    }
+
+    #[test]
+    fn test_parse_cedict() {
+        let cedict = "# CC-CEDICT\n傳統 传统 [chuan2 tong3] /tradition/traditional/convention/\n綠 绿 [lu:4] /green/\n蘋果 苹果 [ping2 guo3] /apple/CL:個|个[ge4],顆|颗[ke1]/\n";
+        let cedict = CedictDictionary::parse(cedict, CedictMode::Prefer);
+        let word_entry = cedict.query_by_chinese("传统");
+        assert_eq!(word_entry.len(), 1);
+        assert_eq!(word_entry[0].traditional, "傳統");
+        assert_eq!(word_entry[0].pinyin_marks, "chuán tǒng");
+        assert_eq!(word_entry[0].english, vec!["tradition", "traditional", "convention"]);
+        assert_eq!(cedict.query_by_chinese("綠")[0].pinyin_marks, "lǜ");
+        let measure_words = &cedict.query_by_chinese("蘋果")[0].measure_words;
+        assert_eq!(measure_words.len(), 2);
+        assert_eq!(measure_words[1].simplified, "颗");
+        assert_eq!(cedict.query_by_chinese("蘋果")[0].english, vec!["apple"]);
+        //Falls back to the bundled dictionary
+        assert!(!cedict.query_by_chinese("時尚").is_empty());
+    }
+
+    #[test]
+    fn test_parse_glossary() {
+        let tempdir = tempfile::Builder::new().prefix("test_parse_glossary").tempdir().unwrap();
+        let csv_glossary = tempdir.path().join("glossary.csv");
+        std::fs::write(&csv_glossary, "基金會,foundation\n時尚,\"fashion, style\"\n").unwrap();
+        let glossary = parse_glossary(&csv_glossary).unwrap();
+        assert_eq!(glossary["基金會"], "foundation");
+        assert_eq!(glossary["時尚"], "fashion, style");
+        let json_glossary = tempdir.path().join("glossary.json");
+        std::fs::write(&json_glossary, r#"{"基金會": "foundation"}"#).unwrap();
+        assert_eq!(parse_glossary(&json_glossary).unwrap()["基金會"], "foundation");
+    }
+
+    #[test]
+    fn test_parse_components() {
+        let tempdir = tempfile::Builder::new().prefix("test_parse_components").tempdir().unwrap();
+        let components_path = tempdir.path().join("dictionary.txt");
+        std::fs::write(&components_path, concat!(
+            r#"{"character":"好","definition":"good","pinyin":["hǎo"],"decomposition":"⿰女子","radical":"女"}"#, "\n",
+            r#"{"character":"㐅","decomposition":"？","radical":"丿"}"#, "\n",
+        )).unwrap();
+        let components = parse_components(&components_path).unwrap();
+        assert_eq!(components["好"], "⿰女子 (radical 女)");
+        assert!(!components.contains_key("㐅"));
+    }
+
+    #[test]
+    fn test_dictionary_definition_fields() {
+        let cedict = CedictDictionary::parse("傳統 传统 [chuan2 tong3] /tradition/traditional/convention/\n", CedictMode::Prefer);
+        let word_entries = cedict.query_by_chinese("傳統");
+        assert_eq!(DictionaryDefinitionConfig::default().build_definition(&word_entries), "tradition, traditional, convention");
+        let config = DictionaryDefinitionConfig {
+            fields: vec![DefinitionField::ConciseEnglish, DefinitionField::English],
+            separator: "; ".to_string(),
+            field_separator: " | ".to_string(),
+            numbered: false,
+        };
+        assert_eq!(config.build_definition(&word_entries), "tradition | tradition; traditional; convention");
+        let config = DictionaryDefinitionConfig { fields: vec![DefinitionField::ConciseEnglish, DefinitionField::ConciseEnglish], ..config };
+        assert_eq!(config.build_definition(&word_entries), "tradition");
+    }
+
+    #[test]
+    fn test_numbered_senses() {
+        let cedict = CedictDictionary::parse("傳統 传统 [chuan2 tong3] /tradition/traditional/convention/\n綠 绿 [lu:4] /green/\n", CedictMode::Prefer);
+        let config = DictionaryDefinitionConfig { numbered: true, ..DictionaryDefinitionConfig::default() };
+        assert_eq!(config.build_definition(&cedict.query_by_chinese("傳統")), "<ol class=senses><li>tradition</li><li>traditional</li><li>convention</li></ol>");
+        assert_eq!(config.build_definition(&cedict.query_by_chinese("綠")), "green");
+    }
+
+    #[test]
+    fn test_pinyin_source() {
+        for word in ["時尚", "綠", "蘋果", "學習", "喜歡"] {
+            let entry = query_by_chinese(word).into_iter().find(|entry| entry.traditional == word).unwrap();
+            assert_eq!(derive_pinyin_marks(&entry.pinyin_numbers), entry.pinyin_marks, "{}", word);
+        }
+    }
+}
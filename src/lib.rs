@@ -0,0 +1,1292 @@
+use std::{collections::{HashMap, HashSet}, fmt, fs::File, path::{Path, PathBuf}, time::Duration, sync::Arc};
+use chinese_dictionary::{tokenize, WordEntry};
+use genanki_rs::{Model, Package};
+use itertools::{Itertools, izip};
+use log::{error, info, warn, debug};
+use reqwest::Client;
+use tokio::sync::{OnceCell, Mutex};
+
+pub mod cache;
+pub mod checkpoint;
+pub mod config;
+pub mod deck;
+pub mod dictionary;
+pub mod pinyin;
+pub mod providers;
+
+pub use config::parse_config;
+pub use pinyin::convert_pinyin_to_zhuyin;
+pub use providers::{build_client, run_checks, tts::get_available_voices};
+
+use crate::{checkpoint::{Checkpoint, write_failed_rows}, config::{CardTemplate, DefinitionStrategy, GenankiConfig, InputRow, MandarinConfig, MandarinReading, MandarinScript, PricingConfig, ReadingLayout, TtsProviderConfig}, deck::{BuiltNote, CLOZE_MODEL_NAME, SENTENCE_MODEL_FIELDS, SENTENCE_MODEL_NAME, WORD_MODEL_FIELDS, WORD_MODEL_NAME, WordFields, add_notes_to_anki, build_comparable_hanzi, build_note, build_sentence_fields, has_required_fields, init_deck, parse_existing_notes, post_process_fields, write_package_atomically}, dictionary::{CEDICT, COMPONENTS, CedictDictionary, DeriveZhuyin, GLOSSARY, Syllable, build_dictionary_definition, lookup_components, lookup_glossary, parse_components, parse_glossary, query_by_chinese}, pinyin::{convert_pinyin_marks_to_numbers, pinyin_numbers_to_ipa, remove_tone_numbers, remove_zhuyin_tones}, providers::{RateLimiter, validate_provider_keys}, providers::openai::{OPENAI_RATE_LIMITER, build_measure_word_string, get_example_sentence, get_measure_word, get_similar_words}, providers::translation::{get_translation, get_translations, get_transliteration}, providers::tts::{AudioFile, AzureTtsProvider, get_slow_tts, get_tts, synthesise_batch}};
+
+static CONFIG: OnceCell<GenankiConfig> = OnceCell::const_new();
+//Stands in for a literal asterisk between reading the input and building the fields, so only highlights are stars
+const LITERAL_ASTERISK: char = '\u{E000}';
+
+//Converts the configured delimiter to stars, and escaped delimiters (and any other asterisks) to LITERAL_ASTERISK
+fn normalise_highlights(text: &str, delimiter: &str) -> String {
+    let mut normalised = String::new();
+    let mut rest = text;
+    while let Some(char) = rest.chars().next() {
+        if let Some(after_escape) = rest.strip_prefix('\\').and_then(|after_backslash| after_backslash.strip_prefix(delimiter)).filter(|_| !delimiter.is_empty()) {
+            normalised.push_str(&delimiter.replace('*', &LITERAL_ASTERISK.to_string()));
+            rest = after_escape;
+        } else if let Some(after_delimiter) = rest.strip_prefix(delimiter).filter(|_| !delimiter.is_empty()) {
+            normalised.push('*');
+            rest = after_delimiter;
+        } else {
+            normalised.push(match char {
+                '*' => LITERAL_ASTERISK,
+                _ => char,
+            });
+            rest = &rest[char.len_utf8()..];
+        }
+    }
+    normalised
+}
+
+fn restore_literal_asterisks(text: &str) -> String {
+    text.replace(LITERAL_ASTERISK, "*")
+}
+
+//Words whose tokens the dictionary returned nothing for, in the order they're first found, leaving out any already in the glossary
+fn find_missing_words<'a>(tokens: impl Iterator<Item = &'a Token>) -> Vec<String> {
+    tokens
+        .filter(|token| token.word_entry.as_ref().is_some_and(|word_entry| word_entry.is_empty()))
+        .filter(|token| !is_blank_hanzi(&token.text) && lookup_glossary(&token.text).is_none())
+        .map(|token| token.text.clone())
+        .unique()
+        .collect_vec()
+}
+
+//One word per line, so a definition can be added after a comma and the file used as the glossary
+fn write_missing_words(missing_words_file: &Path, missing_words: &[String]) -> Result<(), Error> {
+    let mut missing_words_writer = csv::WriterBuilder::new().from_path(missing_words_file)?;
+    for missing_word in missing_words {
+        missing_words_writer.write_record([missing_word])?;
+    }
+    missing_words_writer.flush()?;
+    info!("Wrote {} words missing from the dictionary to {}", missing_words.len(), missing_words_file.display());
+    Ok(())
+}
+
+fn expand_input_path(input: &str) -> Vec<PathBuf> { //Matching files are sorted so the rows are always read in the same order
+    let input_path = Path::new(input);
+    let Some(pattern) = input_path.file_name().and_then(|file_name| file_name.to_str()).filter(|file_name| file_name.contains('*')) else {
+        return vec![input_path.to_owned()];
+    };
+    let dir = match input_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let input_paths = std::fs::read_dir(dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_file() && path.file_name().and_then(|file_name| file_name.to_str()).is_some_and(|file_name| matches_wildcard(pattern, file_name)))
+        .sorted()
+        .collect_vec();
+    if input_paths.is_empty() {
+        warn!("No input files match {}", input);
+    }
+    input_paths
+}
+
+fn matches_wildcard(pattern: &str, text: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == text,
+        Some((prefix, rest)) => text.strip_prefix(prefix).is_some_and(|text| {
+            text.char_indices().map(|(index, _)| index).chain(std::iter::once(text.len())).any(|index| matches_wildcard(rest, &text[index..]))
+        }),
+    }
+}
+
+//Where a definition came from, shown on the card when cards.definition_source is on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DefinitionSource {
+    Input,
+    Glossary,
+    Dictionary,
+    Azure,
+    DictionaryAndAzure,
+}
+
+impl fmt::Display for DefinitionSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DefinitionSource::Input => write!(f, "Input"),
+            DefinitionSource::Glossary => write!(f, "Glossary"),
+            DefinitionSource::Dictionary => write!(f, "Dictionary"),
+            DefinitionSource::Azure => write!(f, "Azure"),
+            DefinitionSource::DictionaryAndAzure => write!(f, "Dictionary + Azure"),
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct CostEstimate {
+    pub rows: usize,
+    pub tts_characters: usize,
+    pub translation_characters: usize,
+    pub openai_requests: usize,
+}
+
+impl CostEstimate {
+    //Follows what process_word and process_sentence will request, without requesting it.
+    //Example sentences aren't known yet so their audio isn't counted. The glossary is passed in rather than read from GLOSSARY so tests can give their own
+    fn add_row(&mut self, tokenised_sentence: &[Token], has_definition: bool, config: &GenankiConfig, glossary: Option<&HashMap<String, String>>) {
+        let plain_sentence = MandarinSentence::build_plain_sentence_from_tokens(tokenised_sentence);
+        let characters = plain_sentence.chars().count();
+        let is_word = tokenised_sentence.len() == 1;
+        self.rows += 1;
+        if config.cards.audio && config.tts_provider != TtsProviderConfig::Silent {
+            self.tts_characters += match config.cards.slow_audio {
+                true => characters * 2,
+                false => characters,
+            };
+        }
+        let needs_translation = match is_word {
+            //Glossary words are never translated, whatever the definition strategy
+            true => !glossary.is_some_and(|glossary| glossary.contains_key(&tokenised_sentence[0].text)) && (config.definition_strategy != DefinitionStrategy::Dictionary || tokenised_sentence[0].build_definition(&config.mandarin.reading).is_none()),
+            false => true,
+        };
+        if !has_definition && needs_translation {
+            self.translation_characters += characters;
+        }
+        if is_word && config.openai.is_some() {
+            self.openai_requests += [config.cards.similar_words, config.cards.measure_word, config.cards.example_sentence].into_iter().filter(|enabled| *enabled).count();
+        }
+    }
+
+    pub fn total(&self, pricing_config: &PricingConfig) -> f64 {
+        self.tts_characters as f64 / 1_000_000.0 * pricing_config.tts_per_million_characters
+            + self.translation_characters as f64 / 1_000_000.0 * pricing_config.translation_per_million_characters
+            + self.openai_requests as f64 * pricing_config.openai_per_request
+    }
+
+    pub fn build_report(&self, pricing_config: &PricingConfig) -> Vec<String> {
+        vec![
+            format!("{} rows to build", self.rows),
+            format!("Text to speech: {} characters", self.tts_characters),
+            format!("Translation: {} characters", self.translation_characters),
+            format!("OpenAI: {} requests", self.openai_requests),
+            format!("Estimated cost: {:.2}", self.total(pricing_config)),
+        ]
+    }
+}
+
+//Only counts the rows which aren't already in the checkpoint. Nothing is sent to any API
+pub fn estimate_cost(retry_failed: bool) -> Result<CostEstimate, Error> {
+    let config = CONFIG.get().unwrap();
+    let completed = Checkpoint::read_completed(&config.checkpoint_dir);
+    let mut cost_estimate = CostEstimate::default();
+    for row in read_rows(retry_failed)?.iter().filter(|row| !completed.contains_key(&row.row_key)) {
+        cost_estimate.add_row(&row.tokenised_sentence, row.definition.is_some(), config, GLOSSARY.get());
+    }
+    Ok(cost_estimate)
+}
+
+#[derive(Debug)]
+pub struct Token {
+    pub text: String,
+    pub word_entry: Option<Vec<&'static WordEntry>> //I believe this should only ever have one word entry inside, but I'm not certain.
+}
+
+impl Token {
+    fn build_definition(&self, reading: &MandarinReading) -> Option<String> { //Returns none if there is no word entry vec, or if the vec doesn't contain any english translation information.
+        if let Some(polyphone_definition) = self.build_polyphone_definition(reading) {
+            return Some(polyphone_definition);
+        }
+        match &self.word_entry {
+            Some(word_entry) => {
+                let definition = build_dictionary_definition(word_entry);
+                match definition.len() {
+                    0 => None,
+                    _ => Some(definition),
+                }
+            },
+            None => None,
+        }
+    }
+    fn build_polyphone_definition(&self, reading: &MandarinReading) -> Option<String> { //Returns none unless the token is a 多音字, in which case each reading gets its own definition line
+        let word_entry = self.word_entry.as_ref()?;
+        let exact_entries = word_entry.iter()
+            .filter(|word| word.traditional == self.text || word.simplified == self.text)
+            .filter(|word| !word.english.is_empty())
+            .collect_vec();
+        let pinyin_readings = exact_entries.iter().map(|word| &word.pinyin_numbers).unique().collect_vec();
+        if pinyin_readings.len() < 2 {
+            return None;
+        }
+        let definition = pinyin_readings.into_iter().map(|pinyin_reading| {
+            let reading_entries = exact_entries.iter().filter(|word| &word.pinyin_numbers == pinyin_reading).map(|word| **word).collect_vec();
+            let reading_str = reading.build_entries_reading(&reading_entries[..1]);
+            format!("{}: {}", reading_str, build_dictionary_definition(&reading_entries))
+        }).join("<br>");
+        Some(definition)
+    }
+    fn build_collocations(&self, mandarin_config: &MandarinConfig) -> String { //Measure words from the dictionary, empty if there are none
+        let Some(word_entry) = &self.word_entry else {
+            return String::new();
+        };
+        word_entry.iter()
+            .flat_map(|word| &word.measure_words)
+            .unique_by(|measure_word| &measure_word.traditional)
+            .map(|measure_word| {
+                let hanzi = match mandarin_config.script {
+                    MandarinScript::Traditional => &measure_word.traditional,
+                    MandarinScript::Simplified => &measure_word.simplified,
+                };
+                let reading = mandarin_config.reading.build_entries_reading(&[measure_word]);
+                format!("{} ({})", hanzi, reading)
+            })
+            .join(", ")
+    }
+    //The dictionary has no part of speech, but CC-CEDICT only lists measure words for nouns and glosses verbs as "to ...".
+    //The HSK level is added too when the word has one
+    fn build_part_of_speech_tags(&self) -> Vec<String> {
+        let Some(word_entry) = &self.word_entry else {
+            return Vec::new();
+        };
+        let exact_entries = word_entry.iter().filter(|word| word.traditional == self.text || word.simplified == self.text).collect_vec();
+        let mut tags = Vec::new();
+        if let Some(hsk) = exact_entries.iter().map(|word| word.hsk).filter(|hsk| *hsk > 0).min() {
+            tags.push(format!("hsk{}", hsk));
+        }
+        if exact_entries.iter().any(|word| !word.measure_words.is_empty()) {
+            tags.push(String::from("noun"));
+        }
+        if exact_entries.iter().flat_map(|word| &word.english).any(|english| english.starts_with("to ")) {
+            tags.push(String::from("verb"));
+        }
+        tags
+    }
+    fn build_toneless_reading(&self, reading: &MandarinReading) -> Option<String> { //From the first dictionary entry's reading, like the IPA
+        let word = self.word_entry.as_ref()?.first()?;
+        Some(reading.build_reading_string(
+            || remove_zhuyin_tones(&word.derive_zhuyin()),
+            || remove_tone_numbers(&word.pinyin_numbers),
+            || remove_tone_numbers(&word.pinyin_numbers),
+        ))
+    }
+    fn build_ipa(&self) -> Option<String> { //From the first dictionary entry's reading, none if any syllable can't be mapped
+        let word = self.word_entry.as_ref()?.first()?;
+        pinyin_numbers_to_ipa(&word.pinyin_numbers)
+    }
+    fn build_ruby(&self, reading: &MandarinReading) -> Option<String> { //Each character annotated with its own syllable, or the whole word at once if they don't line up
+        let word_entry = self.word_entry.as_ref()?;
+        let word = word_entry.iter().find(|word| word.traditional == self.text || word.simplified == self.text).or(word_entry.first())?;
+        let pinyin_numbers_syllables = word.pinyin_numbers.split_whitespace().collect_vec();
+        let pinyin_marks_syllables = word.pinyin_marks.split_whitespace().collect_vec();
+        let characters = self.text.chars().collect_vec();
+        match pinyin_numbers_syllables.len() == characters.len() && pinyin_marks_syllables.len() == characters.len() {
+            true => Some(izip!(characters, pinyin_numbers_syllables, pinyin_marks_syllables)
+                .map(|(character, pinyin_numbers, pinyin_marks)| format_ruby(&character.to_string(), &reading.build_entries_reading(&[&Syllable { pinyin_numbers, pinyin_marks }])))
+                .join("")),
+            false => Some(format_ruby(&self.text, &reading.build_entries_reading(&[*word]))),
+        }
+    }
+    fn build_reading(&self, reading: &MandarinReading) -> Option<String> { //Every distinct reading of the token in the configured format
+        let reading = self.word_entry.as_ref()?.iter()
+            .map(|word| reading.build_entries_reading(&[*word]))
+            .unique().join(",");
+        match reading.len() {
+            0 => None,
+            _ => Some(reading),
+        }
+    }
+}
+
+fn format_ruby(base: &str, annotation: &str) -> String {
+    format!("<ruby>{}<rt>{}</rt></ruby>", base, annotation)
+}
+
+pub struct MandarinSentence {
+    pub raw_sentence: String,
+    pub tokens: Vec<Token>
+}
+
+impl MandarinSentence {
+    fn build_plain_sentence(&self) -> String {
+        MandarinSentence::build_plain_sentence_from_tokens(&self.tokens)
+    }
+    fn build_plain_sentence_from_tokens(tokens: &[Token]) -> String { //Removing the stars can leave doubled or trailing spaces, so whitespace is collapsed for the TTS
+        let plain_sentence = tokens.iter().map(|token| match token.text.as_str() {
+            "*" => String::from(""),
+            _ => token.text.clone()
+        }).join("").split_whitespace().join(" ");
+        restore_literal_asterisks(&plain_sentence)
+    }
+    fn build_word_gloss(&self, reading: &MandarinReading) -> String { //A table of each distinct word in the sentence with its reading and definition
+        let rows = self.tokens.iter()
+            .filter(|token| token.word_entry.as_ref().is_some_and(|word_entry| !word_entry.is_empty()))
+            .unique_by(|token| &token.text)
+            .map(|token| format!("<tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+                token.text, token.build_reading(reading).unwrap_or_default(), token.build_definition(reading).unwrap_or_default()))
+            .join("");
+        format!("<table class=gloss>{}</table>", rows)
+    }
+    fn build_note_sentence(&self) -> String {
+        self.build_highlighted_sentence(|token| token.text.clone())
+    }
+    fn build_ruby_sentence(&self, reading: &MandarinReading) -> String { //Words which aren't in the dictionary are left unannotated
+        self.build_highlighted_sentence(|token| token.build_ruby(reading).unwrap_or_else(|| token.text.clone()))
+    }
+    fn build_highlighted_sentence(&self, build_token: impl Fn(&Token) -> String) -> String { //Starred text is wrapped in a span so it can be highlighted
+        let mut have_seen_star = false;
+        let mut note_sentence = self.tokens.iter().map(|token| match token.text.as_str() {
+            "*" => {
+                let star_replacement = match have_seen_star {
+                    false => String::from("<span class=starred>"),
+                    true => String::from("</span>"),
+                };
+                have_seen_star = !have_seen_star;
+                star_replacement
+            },
+            _ => build_token(token)
+        }).join("");
+        if have_seen_star { //Unbalanced stars, close the span so the card is still valid html
+            note_sentence.push_str("</span>");
+        }
+        note_sentence
+    }
+    fn build_cloze_sentence(&self) -> String { //Each starred span becomes its own cloze deletion
+        let mut cloze_count = 0;
+        let mut have_seen_star = false;
+        let mut cloze_sentence = self.tokens.iter().map(|token| match token.text.as_str() {
+            "*" => {
+                have_seen_star = !have_seen_star;
+                match have_seen_star {
+                    true => {
+                        cloze_count += 1;
+                        format!("{{{{c{}::", cloze_count)
+                    },
+                    false => String::from("}}"),
+                }
+            },
+            _ => token.text.clone()
+        }).join("");
+        if have_seen_star {
+            cloze_sentence.push_str("}}");
+        }
+        cloze_sentence
+    }
+    fn has_stars(&self) -> bool {
+        self.tokens.iter().any(|token| token.text == "*")
+    }
+}
+
+pub fn tokenise_sentence(original_sentence: &str) -> Vec<Token> {
+    let tokens = tokenize(original_sentence);
+    let mut token_at_index: Vec<Token> = Vec::new();
+    let mut current_index = 0;
+    for token in tokens {
+        //Checked slicing, so a token which doesn't line up with the sentence is skipped with a warning instead of panicking
+        let Some(index_of_token) = original_sentence.get(current_index..).and_then(|rest| rest.find(token)).map(|index| index + current_index) else {
+            warn!("Unable to find token {} in sentence {}, skipping it", token, original_sentence);
+            continue;
+        };
+        if index_of_token > current_index {
+            match original_sentence.get(current_index..index_of_token) {
+                Some(non_mandarin_text) => for non_mandarin_char in non_mandarin_text.chars() {
+                    let non_mandarin_token = Token { text: non_mandarin_char.to_string(), word_entry: Option::None};
+                    token_at_index.push(non_mandarin_token);
+                },
+                None => warn!("Skipping text which isn't on a character boundary in sentence {}", original_sentence),
+            }
+            current_index = index_of_token;
+        }
+        let word_entry = query_by_chinese(token);
+        let value = Token { text: token.to_string(), word_entry: Option::Some(word_entry)};
+        token_at_index.push(value);
+        current_index += token.len()
+    }
+    match original_sentence.get(current_index..) {
+        Some(non_mandarin_text) => for non_mandarin_char in non_mandarin_text.chars() {
+            let non_mandarin_token = Token { text: non_mandarin_char.to_string(), word_entry: Option::None};
+            token_at_index.push(non_mandarin_token);
+        },
+        None => warn!("Skipping text which isn't on a character boundary at the end of sentence {}", original_sentence),
+    }
+    token_at_index
+}
+
+fn build_note_reading(reading: &str) -> String {
+    let mut have_seen_star = false;
+    let mut note_reading = reading.chars().map(|char| match char {
+        '*' => {
+            let star_replacement = match have_seen_star {
+                false => String::from("<span class=starred>"),
+                true => String::from("</span>")
+            };
+            have_seen_star = !have_seen_star;
+            star_replacement
+        }
+        _ => char.to_string()
+    }).collect::<String>();
+    if have_seen_star { //Unbalanced stars, close the span so the card is still valid html
+        note_reading.push_str("</span>");
+    }
+    note_reading
+}
+
+//One tab separated line per token: the text, whether the dictionary matched it, its reading and its definition
+pub fn build_token_report(tokens: &[Token], reading: &MandarinReading) -> Vec<String> {
+    tokens.iter().map(|token| {
+        let matched = match &token.word_entry {
+            Some(word_entry) if !word_entry.is_empty() => "matched",
+            Some(_) => "unmatched",
+            None => "not mandarin",
+        };
+        format!("{}\t{}\t{}\t{}", token.text, matched, token.build_reading(reading).unwrap_or_default(), token.build_definition(reading).unwrap_or_default())
+    }).collect_vec()
+}
+
+//For text from the dictionary, Azure or OpenAI, which shouldn't be interpreted as html in the note
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+//Han characters count as alphabetic, so anything left is whitespace, punctuation or highlight stars
+fn is_blank_hanzi(hanzi: &str) -> bool {
+    !hanzi.chars().any(|char| char.is_alphanumeric())
+}
+
+fn has_balanced_stars(text: &str) -> bool {
+    text.matches('*').count().is_multiple_of(2)
+}
+
+//None if the word can't be made into a note, an error if a request it needed failed
+#[allow(clippy::too_many_arguments)]
+pub async fn process_word(word_model: Model, token: &Token, definition: Option<(String, DefinitionSource)>, reading: Option<String>, voice: Option<String>, tempdir: PathBuf, client: Client, mutex: Arc<Mutex<i32>>) -> Result<Option<BuiltNote>, Error> {
+    //Exit prematurely if the word is not Mandarin
+    match &token.word_entry {
+        Some(word_entry) => {
+            if word_entry.is_empty() {
+                warn!("Word wasn't recognisably Mandarin");
+                return Ok(None)
+            }
+        },
+        None => {
+            warn!("Word wasn't recognisable Mandarin");
+            return Ok(None)
+        },
+    };
+
+    let config = CONFIG.get().unwrap();
+    
+    let (definition, definition_source) = match definition.or_else(|| lookup_glossary(&token.text).map(|definition| (definition, DefinitionSource::Glossary))) {
+        Some(definition) => definition,
+        None => match (&config.definition_strategy, token.build_definition(&config.mandarin.reading)) {
+            (DefinitionStrategy::Dictionary, Some(definition)) => (definition, DefinitionSource::Dictionary),
+            (DefinitionStrategy::Merge, Some(definition)) => (merge_definitions(definition, get_translation(&token.text, &client, &config.azure).await?), DefinitionSource::DictionaryAndAzure),
+            _ => (get_translation(&token.text, &client, &config.azure).await?, DefinitionSource::Azure),
+        },
+    };
+    debug!("Built Word Definition: {} from {}", definition, definition_source);
+    let definition_source = match config.cards.definition_source {
+        true => definition_source.to_string(),
+        false => String::new(),
+    };
+    let azure_config = config.azure.with_voice_name(voice.as_deref());
+    let audio = match config.cards.audio {
+        true => get_tts(&token.text, tempdir.clone(), &client, &azure_config).await,
+        false => None,
+    };
+    let slow_audio = match config.cards.audio && config.cards.slow_audio {
+        true => get_slow_tts(&token.text, tempdir.clone(), &client, &azure_config).await,
+        false => None,
+    };
+    let similar_words_string = match (config.cards.similar_words, &config.openai) {
+        (true, Some(openai_config)) => {
+            let similar_words = get_similar_words(&token.text, &client, openai_config, &config.mandarin.script).await?;
+            let mut similar_words_with_readings = Vec::new();
+            for similar_word in similar_words {
+                let reading_str = similar_word.build_reading(&client, config, Arc::clone(&mutex)).await?;
+                similar_words_with_readings.push((similar_word, reading_str));
+            }
+            config.style.similar_words_layout.build_similar_words(&similar_words_with_readings, &config.style.similar_words_separator)
+        },
+        _ => String::new(),
+    };
+    debug!("Built Similar Words for Note: {:#?}", similar_words_string);
+    let measure_word_string = match (config.cards.measure_word, &config.openai) {
+        (true, Some(openai_config)) => get_measure_word(&token.text, &client, openai_config, &config.mandarin.script).await?
+            .map(|measure_word| build_measure_word_string(&measure_word, &config.mandarin.reading))
+            .unwrap_or_default(),
+        _ => String::new(),
+    };
+    debug!("Built Measure Word for Note: {:#?}", measure_word_string);
+
+    let example_sentence = match (config.cards.example_sentence, &config.openai) {
+        (true, Some(openai_config)) => get_example_sentence(&token.text, &client, openai_config, &config.mandarin.script).await?,
+        _ => None,
+    };
+    debug!("Built Example Sentence for Note: {:#?}", example_sentence);
+    let example_audio = match (&example_sentence, config.cards.audio && config.cards.example_sentence_audio) {
+        (Some(example_sentence), true) => get_tts(example_sentence, tempdir.clone(), &client, &azure_config).await,
+        _ => None,
+    };
+
+    let ipa = match config.cards.ipa {
+        true => token.build_ipa().unwrap_or_default(),
+        false => String::new(),
+    };
+    let toneless_reading = match config.cards.templates.contains(&CardTemplate::Tones) {
+        true => token.build_toneless_reading(&config.mandarin.reading).unwrap_or_default(),
+        false => String::new(),
+    };
+
+    let reading = reading.unwrap_or_else(|| match config.mandarin.reading_layout {
+        ReadingLayout::Separate => token.build_reading(&config.mandarin.reading).unwrap_or_default(),
+        ReadingLayout::Ruby => token.build_ruby(&config.mandarin.reading).unwrap_or_default(),
+    });
+
+    let word_fields = WordFields {
+        definition,
+        audio: audio.as_ref(),
+        reading,
+        similar_words: similar_words_string,
+        measure_word: measure_word_string,
+        components: lookup_components(&token.text),
+        ipa,
+        example_sentence,
+        example_audio: example_audio.as_ref(),
+        definition_source,
+        slow_audio: slow_audio.as_ref(),
+        toneless_reading,
+    }.build(token, &config.mandarin);
+    let word_fields = match &config.post_process {
+        Some(post_process_config) => post_process_fields(WORD_MODEL_NAME, WORD_MODEL_FIELDS, word_fields, post_process_config).await,
+        None => word_fields,
+    };
+    if !has_required_fields(&token.text, WORD_MODEL_FIELDS, &word_fields) {
+        return Ok(None);
+    }
+    let word_note = build_note(word_model, WORD_MODEL_NAME, &word_fields);
+    debug!("Built Word Note");
+
+    let tags = match config.cards.part_of_speech_tags {
+        true => token.build_part_of_speech_tags(),
+        false => Vec::new(),
+    };
+
+    Ok(Some(BuiltNote { model_name: WORD_MODEL_NAME, note: word_note, fields: word_fields, media: audio.into_iter().chain(example_audio).chain(slow_audio).collect_vec(), tags }))
+}
+
+fn merge_definitions(dictionary_definition: String, translation: String) -> String { //The translation is left out if the dictionary already says the same thing
+    match dictionary_definition.to_lowercase().contains(&translation.to_lowercase()) {
+        true => dictionary_definition,
+        false => format!("{}<br>{}", dictionary_definition, translation),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn process_sentence(sentence_model: Model, model_name: &'static str, sentence: &MandarinSentence, definition: Option<(String, DefinitionSource)>, reading: Option<String>, voice: Option<String>, tempdir: PathBuf, client: Client, mutex: Arc<Mutex<i32>>) -> Result<Option<BuiltNote>, Error> {
+    //Exit prematurely if none of the sentence is mandarin
+    if !sentence.tokens.iter().any(|token| token.word_entry.as_ref().is_some_and(|word_entry| !word_entry.is_empty())) {
+        warn!("Sentence had no recognisable Mandarin characters");
+        return Ok(None);
+    }
+
+    let config = CONFIG.get().unwrap();
+
+    let plain_sentence = sentence.build_plain_sentence();
+    debug!("Built Plain Sentence: {}", plain_sentence);
+
+    let note_sentence = match model_name {
+        CLOZE_MODEL_NAME => sentence.build_cloze_sentence(),
+        _ => sentence.build_note_sentence(),
+    };
+    debug!("Built Sentence for Note: {}", note_sentence);
+    let (definition, definition_source) = match definition {
+        Some(definition) => definition,
+        None => (get_translation(&plain_sentence, &client, &config.azure).await?, DefinitionSource::Azure)
+    };
+    debug!("Built Definition: {} from {}", definition, definition_source);
+    let definition_source = match config.cards.definition_source {
+        true => definition_source.to_string(),
+        false => String::new(),
+    };
+    let note_reading = match reading {
+        Some(reading) => build_note_reading(&reading), //Supplied in the input, so used as is
+        None if config.mandarin.reading_layout == ReadingLayout::Ruby => sentence.build_ruby_sentence(&config.mandarin.reading), //Aligned from the dictionary, so Azure isn't needed
+        None => {
+            let (pinyin_reading, zhuyin_reading) = get_transliteration(&sentence.raw_sentence, &client, config, mutex).await?;
+            build_note_reading(&config.mandarin.reading.build_reading_string(|| zhuyin_reading, || pinyin_reading.clone(), || convert_pinyin_marks_to_numbers(&pinyin_reading)))
+        },
+    };
+    debug!("Built Reading for Note: {}", note_reading);
+    let azure_config = config.azure.with_voice_name(voice.as_deref());
+    let audio = match config.cards.audio {
+        true => get_tts(&plain_sentence, tempdir.clone(), &client, &azure_config).await,
+        false => None,
+    };
+    let slow_audio = match config.cards.audio && config.cards.slow_audio {
+        true => get_slow_tts(&plain_sentence, tempdir, &client, &azure_config).await,
+        false => None,
+    };
+
+    let word_gloss = match config.cards.word_gloss {
+        true => sentence.build_word_gloss(&config.mandarin.reading),
+        false => String::new(),
+    };
+
+    let sentence_fields = build_sentence_fields(note_sentence, definition, audio.as_ref(), note_reading, word_gloss, definition_source, slow_audio.as_ref());
+    let sentence_fields = match &config.post_process {
+        Some(post_process_config) => post_process_fields(model_name, SENTENCE_MODEL_FIELDS, sentence_fields, post_process_config).await,
+        None => sentence_fields,
+    };
+    if !has_required_fields(&sentence.raw_sentence, SENTENCE_MODEL_FIELDS, &sentence_fields) {
+        return Ok(None);
+    }
+    let sentence_note = build_note(sentence_model, model_name, &sentence_fields);
+    debug!("Built Sentence Note");
+
+    Ok(Some(BuiltNote { model_name, note: sentence_note, fields: sentence_fields, media: audio.into_iter().chain(slow_audio).collect_vec(), tags: Vec::new() }))
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Unable to authenticate with: {}", .providers.join(", "))]
+pub struct InvalidKeyError {
+    providers: Vec<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("{} rows failed in strict mode: {}", .rows.len(), .rows.join(", "))]
+pub struct FailedRowsError {
+    rows: Vec<String>,
+}
+
+//Everything which can stop a run, so callers can tell a bad config from a network outage
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Invalid config: {0}")]
+    Config(#[from] ::config::ConfigError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("Invalid csv: {0}")]
+    Csv(#[from] csv::Error),
+    #[error("Request failed: {0}")]
+    Network(#[from] reqwest::Error),
+    #[error("Invalid json: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Unable to write the package: {0}")]
+    Package(Box<genanki_rs::Error>),
+    #[error("{0}")]
+    Parse(String),
+    #[error("{command} exited with {status}")]
+    Command { command: String, status: std::process::ExitStatus },
+    #[error(transparent)]
+    InvalidKeys(#[from] InvalidKeyError),
+    #[error(transparent)]
+    FailedRows(#[from] FailedRowsError),
+    #[error("AnkiConnect: {0}")]
+    AnkiConnect(String),
+    #[error("Unable to read a fix: {0}")]
+    Prompt(#[from] rustyline::error::ReadlineError),
+}
+
+//Sets the config the rest of the crate reads and loads the files it points to, this must be called once before anything else
+pub fn init(config: GenankiConfig) -> Result<&'static GenankiConfig, Error> {
+    CONFIG.set(config).unwrap();
+    let config = CONFIG.get().unwrap();
+    if let Some(cedict_config) = &config.cedict {
+        CEDICT.set(CedictDictionary::load(cedict_config)).unwrap();
+    }
+    if let Some(glossary_path) = &config.glossary {
+        GLOSSARY.set(parse_glossary(glossary_path)?).unwrap();
+    }
+    if let Some(components_path) = &config.components {
+        COMPONENTS.set(parse_components(components_path)?).unwrap();
+    }
+    if let Some(requests_per_minute) = config.openai.as_ref().and_then(|openai_config| openai_config.requests_per_minute) {
+        OPENAI_RATE_LIMITER.set(RateLimiter::new(requests_per_minute)).unwrap();
+    }
+    Ok(config)
+}
+
+//A row read from the input which still needs to be built
+struct PendingRow {
+    row_key: String,
+    hanzi: String,
+    definition: Option<(String, DefinitionSource)>,
+    reading: Option<String>,
+    tags: Vec<String>,
+    voice: Option<String>,
+    tokenised_sentence: Vec<Token>,
+}
+
+//Every row of every input file, apart from those which are skipped
+fn read_rows(retry_failed: bool) -> Result<Vec<PendingRow>, Error> {
+    let existing_hanzi = match &CONFIG.get().unwrap().existing_notes {
+        Some(existing_notes) => parse_existing_notes(existing_notes, CONFIG.get().unwrap().existing_notes_field)?,
+        None => HashSet::new(),
+    };
+    let mut rows = Vec::new();
+    let mut seen_hanzi = HashSet::new();
+    let input_paths = match retry_failed {
+        true => vec![CONFIG.get().unwrap().failed_rows_file.clone()],
+        false => CONFIG.get().unwrap().input.iter().flat_map(|input| expand_input_path(input)).collect_vec(),
+    };
+    for input_path in input_paths {
+        info!("Reading {}", input_path.display());
+        let mut input_csv_reader = csv::ReaderBuilder::new()
+            .flexible(true)
+            .has_headers(false)
+            .trim(csv::Trim::All)
+            .from_path(&input_path)?;
+        for row in input_csv_reader.records() {
+            let row = match row {
+                Ok(row) => row,
+                Err(error) => {
+                    warn!("Row can't be read, skipping it: {}", error);
+                    continue;
+                },
+            };
+            let row_key = serde_json::to_string(&row.iter().collect_vec()).unwrap();
+            let Some(InputRow { hanzi, definition, reading, tags, voice }) = CONFIG.get().unwrap().columns.read_row(&row) else {
+                warn!("Row has no hanzi column, skipping it: {:?}", row);
+                continue;
+            };
+            let highlight_delimiter = &CONFIG.get().unwrap().highlight_delimiter;
+            let mut hanzi = normalise_highlights(&hanzi, highlight_delimiter);
+            let reading = reading.map(|reading| normalise_highlights(&reading, highlight_delimiter));
+            if is_blank_hanzi(&hanzi) {
+                warn!("Row has no hanzi, only whitespace or punctuation, skipping it: {:?}", row);
+                continue;
+            }
+            let mandarin_config = &CONFIG.get().unwrap().mandarin;
+            if !mandarin_config.script.matches(&hanzi) {
+                match mandarin_config.convert_script {
+                    true => {
+                        let converted_hanzi = mandarin_config.script.convert(&hanzi);
+                        info!("Converted {} to {}: {}", hanzi, mandarin_config.script, converted_hanzi);
+                        hanzi = converted_hanzi;
+                    },
+                    false => warn!("Row isn't all {}, its dictionary lookups and readings may be wrong: {}", mandarin_config.script, hanzi),
+                }
+            }
+            if existing_hanzi.contains(&build_comparable_hanzi(&hanzi)) {
+                info!("Already in the collection, skipping: {}", hanzi);
+                continue;
+            }
+            if CONFIG.get().unwrap().dedupe && !seen_hanzi.insert(build_comparable_hanzi(&hanzi)) {
+                info!("Duplicate of an earlier row, skipping: {}", hanzi);
+                continue;
+            }
+            if !has_balanced_stars(&hanzi) {
+                warn!("Row has an odd number of stars, the highlight will run to the end of the sentence: {:?}", row);
+            }
+            let tokenised_sentence = tokenise_sentence(&hanzi);
+            if !CONFIG.get().unwrap().row_filter.includes(&tokenised_sentence) {
+                debug!("Left out by row_filter, skipping: {}", hanzi);
+                continue;
+            }
+            rows.push(PendingRow { row_key, hanzi, definition: definition.map(|definition| (definition, DefinitionSource::Input)), reading, tags, voice, tokenised_sentence });
+        }
+    }
+    Ok(rows)
+}
+
+//One tab separated line per row which would be built: the hanzi, whether it's a word or sentence, whether it's already in the checkpoint and its definition from the input.
+//Nothing is sent to any API, and no files are written
+pub fn build_dry_run_report(retry_failed: bool) -> Result<Vec<String>, Error> {
+    let completed = Checkpoint::read_completed(&CONFIG.get().unwrap().checkpoint_dir);
+    let rows = read_rows(retry_failed)?;
+    Ok(rows.iter().map(|row| {
+        let kind = match row.tokenised_sentence.len() {
+            1 => "word",
+            _ => "sentence",
+        };
+        let status = match completed.contains_key(&row.row_key) {
+            true => "completed",
+            false => "to build",
+        };
+        let definition = row.definition.as_ref().map(|(definition, _)| definition.as_str()).unwrap_or_default();
+        format!("{}\t{}\t{}\t{}", restore_literal_asterisks(&row.hanzi), kind, status, definition)
+    }).collect_vec())
+}
+
+//Builds a single note from the text, exactly as it would be in the deck, and returns a line per field
+pub async fn preview(text: &str, client: &Client) -> Result<Option<Vec<String>>, Error> {
+    let config = CONFIG.get().unwrap();
+    let tempdir = tempfile::Builder::new().prefix("gen-mandarin-anki-rs").tempdir().unwrap();
+    let (_, _, word_model, sentence_model, cloze_model) = init_deck(&config.model, &config.deck, &config.cards, &config.style);
+    let hanzi = normalise_highlights(text, &config.highlight_delimiter);
+    let tokenised_sentence = tokenise_sentence(&hanzi);
+    let mutex = Arc::new(Mutex::new(0));
+    let built_note = match tokenised_sentence.len() {
+        1 => process_word(word_model, &tokenised_sentence[0], None, None, None, tempdir.path().to_owned(), client.clone(), mutex).await,
+        _ => {
+            let sentence = MandarinSentence { raw_sentence: hanzi, tokens: tokenised_sentence };
+            let (model, model_name) = match config.cards.cloze && sentence.has_stars() {
+                true => (cloze_model, CLOZE_MODEL_NAME),
+                false => (sentence_model, SENTENCE_MODEL_NAME),
+            };
+            process_sentence(model, model_name, &sentence, None, None, None, tempdir.path().to_owned(), client.clone(), mutex).await
+        },
+    }?;
+    Ok(built_note.map(|built_note| built_note.field_names().iter().zip(&built_note.fields).map(|(field_name, field)| format!("{}\t{}", field_name, field)).collect_vec()))
+}
+
+//Reads every input row, builds its note and writes the deck to output_path. With retry_failed only the rows which failed last time are read
+pub async fn generate(client: &Client, retry_failed: bool, output_path: &Path) -> Result<(), Error> {
+    let deadline = CONFIG.get().unwrap().max_runtime_seconds.map(|max_runtime_seconds| tokio::time::Instant::now() + Duration::from_secs(max_runtime_seconds));
+    validate_provider_keys(client, CONFIG.get().unwrap()).await?;
+
+    let tempdir = tempfile::Builder::new().prefix("gen-mandarin-anki-rs").tempdir().unwrap();
+
+    let (mut deck, mut sentence_deck, word_model, sentence_model, cloze_model) = init_deck(&CONFIG.get().unwrap().model, &CONFIG.get().unwrap().deck, &CONFIG.get().unwrap().cards, &CONFIG.get().unwrap().style);
+
+    let mut media: Vec<AudioFile> = Vec::new();
+    let mut handles = Vec::new();
+    let mutex = Arc::new(Mutex::new(0));
+    let checkpoint = Arc::new(Checkpoint::load(&CONFIG.get().unwrap().checkpoint_dir));
+    let mut rows = read_rows(retry_failed)?;
+    if rows.is_empty() && !CONFIG.get().unwrap().write_empty_deck {
+        warn!("There were no rows to process, so {} wasn't written. Check the input files are the ones you meant", output_path.display());
+        return Ok(());
+    }
+    if let Some(missing_words_file) = &CONFIG.get().unwrap().missing_words_file {
+        write_missing_words(missing_words_file, &find_missing_words(rows.iter().flat_map(|row| &row.tokenised_sentence)))?;
+    }
+
+    //Translate every row without a definition up front, so the translator is called in batches rather than once per row
+    let untranslated_rows = rows.iter_mut().filter(|row| !checkpoint.is_completed(&row.row_key)).filter_map(|PendingRow { definition, tokenised_sentence, .. }| {
+        match (definition.is_none(), tokenised_sentence.len()) {
+            (true, 1) if lookup_glossary(&tokenised_sentence[0].text).is_none() && tokenised_sentence[0].build_definition(&CONFIG.get().unwrap().mandarin.reading).is_none() => Some((definition, tokenised_sentence[0].text.clone())),
+            (true, 2..) => Some((definition, MandarinSentence::build_plain_sentence_from_tokens(tokenised_sentence))),
+            _ => None,
+        }
+    }).collect_vec();
+    if !untranslated_rows.is_empty() {
+        let mandarin_texts = untranslated_rows.iter().map(|(_, mandarin_text)| mandarin_text.clone()).collect_vec();
+        let english_texts = get_translations(&mandarin_texts, client, &CONFIG.get().unwrap().azure).await?;
+        for ((definition, _), english_text) in untranslated_rows.into_iter().zip(english_texts) {
+            *definition = Some((english_text, DefinitionSource::Azure));
+        }
+    }
+
+    if CONFIG.get().unwrap().cards.audio && CONFIG.get().unwrap().tts_provider == TtsProviderConfig::AzureBatch {
+        let azure_configs = rows.iter().filter(|row| !checkpoint.is_completed(&row.row_key) && !row.tokenised_sentence.is_empty()).map(|PendingRow { voice, tokenised_sentence, .. }| {
+            let text = match tokenised_sentence.len() {
+                1 => tokenised_sentence[0].text.clone(),
+                _ => MandarinSentence::build_plain_sentence_from_tokens(tokenised_sentence),
+            };
+            (text, CONFIG.get().unwrap().azure.with_voice_name(voice.as_deref()))
+        }).collect_vec();
+        let forvo_max_characters = CONFIG.get().unwrap().forvo.as_ref().map(|forvo_config| forvo_config.max_characters);
+        let slow_audio = CONFIG.get().unwrap().cards.slow_audio;
+        let inputs = azure_configs.iter().flat_map(|(text, azure_config)| {
+            //Forvo is still tried first for short words, so they're only batched when it can't be used
+            let normal = (forvo_max_characters.is_none_or(|max_characters| text.chars().count() > max_characters))
+                .then(|| (text.clone(), AzureTtsProvider { azure_config, rate: None }));
+            let slow = slow_audio.then(|| (text.clone(), AzureTtsProvider { azure_config, rate: Some(&azure_config.speech.slow_rate) }));
+            normal.into_iter().chain(slow)
+        }).collect_vec();
+        synthesise_batch(&inputs, client).await;
+    }
+
+    let mut unfinished_rows = Vec::new();
+    for PendingRow { row_key, hanzi, definition, reading, tags, voice, tokenised_sentence } in rows {
+        let hanzi = hanzi.as_str();
+        if let Some(built_note) = checkpoint.restore(&row_key, &word_model, &sentence_model, &cloze_model) {
+            info!("Already Completed: {}", hanzi);
+            handles.push((row_key.clone(), hanzi.to_owned(), tags.clone(), tokio::spawn(async move { Ok(Some(built_note)) })));
+            continue;
+        }
+        if deadline.is_some_and(|deadline| tokio::time::Instant::now() >= deadline) { //Don't start anything new once the time is up
+            unfinished_rows.push(hanzi.to_owned());
+            continue;
+        }
+        match tokenised_sentence.len() {
+            1 => { 
+                info!("Found Word: {}", hanzi);
+                let model_clone = word_model.clone();
+                let tempdir_clone = tempdir.path().to_owned();
+                let client_clone = client.clone();
+                let mutex_clone = Arc::clone(&mutex);
+                let checkpoint_clone = Arc::clone(&checkpoint);
+                handles.push((row_key.clone(), hanzi.to_owned(), tags.clone(), tokio::spawn(async move {
+                    let built_note = process_word(model_clone, &tokenised_sentence[0], definition, reading, voice, tempdir_clone, client_clone, mutex_clone).await;
+                    if let Ok(Some(built_note)) = &built_note {
+                        checkpoint_clone.record(&row_key, built_note);
+                    }
+                    built_note
+                })));
+            },
+            2.. => {
+                info!("Found Sentence: {}", hanzi);
+                let tokenised_sentence = MandarinSentence { raw_sentence: hanzi.to_owned(), tokens: tokenised_sentence };
+                let (model_clone, model_name) = match CONFIG.get().unwrap().cards.cloze && tokenised_sentence.has_stars() {
+                    true => (cloze_model.clone(), CLOZE_MODEL_NAME),
+                    false => (sentence_model.clone(), SENTENCE_MODEL_NAME),
+                };
+                let tempdir_clone = tempdir.path().to_owned();
+                let client_clone = client.clone();
+                let mutex_clone = Arc::clone(&mutex);
+                let checkpoint_clone = Arc::clone(&checkpoint);
+                handles.push((row_key.clone(), hanzi.to_owned(), tags.clone(), tokio::spawn(async move {
+                    let built_note = process_sentence(model_clone, model_name, &tokenised_sentence, definition, reading, voice, tempdir_clone, client_clone, mutex_clone).await;
+                    if let Ok(Some(built_note)) = &built_note {
+                        checkpoint_clone.record(&row_key, built_note);
+                    }
+                    built_note
+                })));
+            },
+            _ => warn!("Row couldn't be tokenised, skipping it: {}", hanzi),
+        };
+    }
+
+    let mut notes_json = Vec::new();
+    let mut anki_connect_notes = Vec::new();
+    let mut failed_rows = Vec::new();
+    let mut failed_row_keys = Vec::new();
+    for (row_key, hanzi, tags, mut handle) in handles {
+        //Tasks run concurrently once spawned, so awaiting them in order takes no longer than waiting for them all at once
+        let result = match deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, &mut handle).await {
+                Ok(result) => result,
+                Err(_) => {
+                    handle.abort();
+                    unfinished_rows.push(hanzi);
+                    continue;
+                },
+            },
+            None => handle.await,
+        };
+        match result {
+            Ok(Ok(Some(built_note))) => {
+                notes_json.push(built_note.build_json());
+                let tags = tags.into_iter().chain(built_note.tags.iter().cloned()).collect_vec();
+                let deck_config = &CONFIG.get().unwrap().deck;
+                match (&mut sentence_deck, built_note.model_name) {
+                    (Some(sentence_deck), SENTENCE_MODEL_NAME | CLOZE_MODEL_NAME) => {
+                        anki_connect_notes.push(built_note.build_anki_connect_note(&deck_config.build_sentence_deck_name(), &tags));
+                        sentence_deck.add_note(built_note.note.tags(tags));
+                    },
+                    _ => {
+                        anki_connect_notes.push(built_note.build_anki_connect_note(&deck_config.build_deck_name(), &tags));
+                        deck.add_note(built_note.note.tags(tags));
+                    },
+                }
+                media.extend(built_note.media);
+            },
+            Ok(Ok(None)) => {
+                failed_rows.push(hanzi);
+                failed_row_keys.push(row_key);
+            },
+            Ok(Err(error)) => {
+                error!("Failed to build {}: {}", hanzi, error);
+                failed_rows.push(hanzi);
+                failed_row_keys.push(row_key);
+            },
+            Err(join_error) => {
+                error!("Failed to build {}: {}", hanzi, join_error);
+                failed_rows.push(hanzi);
+                failed_row_keys.push(row_key);
+            },
+        }
+    }
+    write_failed_rows(&CONFIG.get().unwrap().failed_rows_file, &failed_row_keys)?;
+    if !failed_rows.is_empty() {
+        warn!("{} rows were left out of the deck: {}", failed_rows.len(), failed_rows.join(", "));
+        if CONFIG.get().unwrap().strict { //Leave the checkpoint in place so the completed rows aren't repeated
+            return Err(FailedRowsError { rows: failed_rows }.into());
+        }
+    }
+
+    if !unfinished_rows.is_empty() {
+        warn!("max_runtime_seconds was reached, {} rows weren't finished and were left out of the deck: {}", unfinished_rows.len(), unfinished_rows.join(", "));
+    }
+
+    if let Some(json_output) = &CONFIG.get().unwrap().json_output {
+        serde_json::to_writer_pretty(File::create(json_output)?, &notes_json)?;
+        info!("Wrote {} notes to {}", notes_json.len(), json_output.display());
+    }
+
+    match &CONFIG.get().unwrap().anki_connect {
+        Some(anki_connect_config) => add_notes_to_anki(&anki_connect_notes, &media, client, anki_connect_config).await?,
+        None => {
+            let mut package = Package::new(std::iter::once(deck).chain(sentence_deck).collect_vec(), media.iter().map(|path| path.file.to_str().unwrap()).collect_vec()).unwrap();
+            write_package_atomically(&mut package, output_path)?;
+        },
+    }
+    //The run completed, so there is nothing left to resume
+    if unfinished_rows.is_empty() {
+        Arc::into_inner(checkpoint).unwrap().clear();
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::{ColumnConfig, build_config}, providers::openai::SimilarWord};
+
+    #[test]
+    fn test_build_polyphone_definition() {
+        let tokens = tokenise_sentence("行");
+        let definition = tokens[0].build_polyphone_definition(&MandarinReading::Pinyin).unwrap();
+        println!("Polyphone Definition: {}", definition);
+        assert!(definition.contains("xíng: to walk"));
+        assert!(definition.contains("háng: row"));
+        assert_eq!(definition.matches("<br>").count(), 1);
+        assert!(tokenise_sentence("時尚")[0].build_polyphone_definition(&MandarinReading::Zhuyin).is_none());
+    }
+
+    #[test]
+    fn test_build_collocations() {
+        let mut mandarin_config = GenankiConfig::for_testing().mandarin;
+        mandarin_config.reading = MandarinReading::Pinyin;
+        let collocations = tokenise_sentence("蘋果")[0].build_collocations(&mandarin_config);
+        println!("Collocations: {}", collocations);
+        assert!(collocations.contains("個 (gè)"));
+        assert_eq!(tokenise_sentence("很")[0].build_collocations(&mandarin_config), "");
+    }
+
+    #[test]
+    fn test_build_note_sentence() {
+        let hanzi = String::from("你今天看起來很*時尚*");
+        let tokens = tokenise_sentence(&hanzi);
+        let sentence = MandarinSentence{raw_sentence: hanzi, tokens};
+        let note_sentence = sentence.build_note_sentence();
+        println!("Note sentence: {}", note_sentence);
+        assert!(note_sentence.contains("</span>"))
+    }
+
+    #[test]
+    fn test_build_ruby_sentence() {
+        let hanzi = String::from("很*時尚*");
+        let tokens = tokenise_sentence(&hanzi);
+        let sentence = MandarinSentence{raw_sentence: hanzi, tokens};
+        assert_eq!(sentence.build_ruby_sentence(&MandarinReading::Pinyin),
+            "<ruby>很<rt>hěn</rt></ruby><span class=starred><ruby>時<rt>shí</rt></ruby><ruby>尚<rt>shàng</rt></ruby></span>");
+        assert_eq!(sentence.build_ruby_sentence(&MandarinReading::Zhuyin),
+            "<ruby>很<rt>ㄏㄣˇ</rt></ruby><span class=starred><ruby>時<rt>ㄕˊ</rt></ruby><ruby>尚<rt>ㄕㄤˋ</rt></ruby></span>");
+    }
+
+    #[test]
+    fn test_build_note_sentence_unbalanced_stars() {
+        let hanzi = String::from("你今天看起來很*時尚");
+        assert!(!has_balanced_stars(&hanzi));
+        let tokens = tokenise_sentence(&hanzi);
+        let sentence = MandarinSentence{raw_sentence: hanzi, tokens};
+        let note_sentence = sentence.build_note_sentence();
+        assert_eq!(note_sentence, "你今天看起來很<span class=starred>時尚</span>");
+        assert_eq!(build_note_reading("ㄕˊ *ㄕㄤˋ"), "ㄕˊ <span class=starred>ㄕㄤˋ</span>");
+    }
+
+    #[test]
+    fn test_parse_csv() {
+        let data = "\
+        我的頭髮太厚了，我要打薄, \"My hair is too thick, I need to thin it out\"
+        我朋友是個街友*基金會*的員工, My friend works at a homelessness charity
+        基金會
+        ";
+        let mut input_csv_reader = csv::ReaderBuilder::new()
+            .flexible(true)
+            .has_headers(false)
+            // .trim(csv::Trim::All)
+            .from_reader(data.as_bytes());
+        let first_row = input_csv_reader.records().next().unwrap().unwrap();
+        println!("First Row: {:?}", first_row);
+        assert_eq!(first_row.len(), 2);
+    }
+
+    #[test]
+    fn test_build_word_gloss() {
+        let sentence = MandarinSentence { raw_sentence: String::from("我喜歡你，我"), tokens: tokenise_sentence("我喜歡你，我") };
+        let word_gloss = sentence.build_word_gloss(&MandarinReading::Pinyin);
+        assert!(word_gloss.starts_with("<table class=gloss><tr><td>我</td><td>wǒ</td>"));
+        assert!(word_gloss.contains("<tr><td>喜歡</td><td>xǐ huan</td>"));
+        assert_eq!(word_gloss.matches("<tr>").count(), 3);
+    }
+
+    #[test]
+    fn test_tokenise_sentence_multi_byte_characters() {
+        let sentence = "我👍🏽喜歡café*你*";
+        let tokens = tokenise_sentence(sentence);
+        assert_eq!(tokens.iter().map(|token| token.text.as_str()).collect::<String>(), sentence);
+        assert!(tokens.iter().any(|token| token.text == "喜歡" && token.word_entry.is_some()));
+    }
+
+    #[test]
+    fn test_build_cloze_sentence() {
+        let sentence = MandarinSentence { raw_sentence: String::from("我*喜歡*你*們"), tokens: tokenise_sentence("我*喜歡*你*們") };
+        assert!(sentence.has_stars());
+        assert_eq!(sentence.build_cloze_sentence(), "我{{c1::喜歡}}你{{c2::們}}");
+        let sentence = MandarinSentence { raw_sentence: String::from("我喜歡你"), tokens: tokenise_sentence("我喜歡你") };
+        assert!(!sentence.has_stars());
+    }
+
+    #[test]
+    fn test_build_plain_sentence() {
+        let build_plain_sentence = |hanzi: &str| MandarinSentence { raw_sentence: hanzi.to_string(), tokens: tokenise_sentence(hanzi) }.build_plain_sentence();
+        assert_eq!(build_plain_sentence("我用*Python*寫程式"), "我用Python寫程式");
+        assert_eq!(build_plain_sentence("我 *喜歡* 你"), "我 喜歡 你");
+        assert_eq!(build_plain_sentence("我 * 喜歡 * 你，好嗎？"), "我 喜歡 你，好嗎？");
+        assert_eq!(build_plain_sentence(" *Hello* world *"), "Hello world");
+        assert_eq!(build_plain_sentence("你今天看起來很*時尚*。"), "你今天看起來很時尚。");
+    }
+
+    #[test]
+    fn test_expand_input_path() {
+        let tempdir = tempfile::Builder::new().prefix("test_expand_input_path").tempdir().unwrap();
+        for file_name in ["food.csv", "chapter1.csv", "chapter2.csv", "chapter2.txt"] {
+            std::fs::write(tempdir.path().join(file_name), "").unwrap();
+        }
+        let pattern = tempdir.path().join("chapter*.csv");
+        assert_eq!(expand_input_path(pattern.to_str().unwrap()), vec![tempdir.path().join("chapter1.csv"), tempdir.path().join("chapter2.csv")]);
+        assert_eq!(expand_input_path("input.csv"), vec![PathBuf::from("input.csv")]);
+        assert!(matches_wildcard("*.csv", "input.csv"));
+        assert!(!matches_wildcard("*.csv", "input.csv.bak"));
+    }
+
+    #[test]
+    fn test_build_part_of_speech_tags() {
+        assert!(tokenise_sentence("蘋果")[0].build_part_of_speech_tags().contains(&String::from("noun")));
+        assert!(tokenise_sentence("走")[0].build_part_of_speech_tags().contains(&String::from("verb")));
+        assert!(!tokenise_sentence("很")[0].build_part_of_speech_tags().contains(&String::from("noun")));
+    }
+
+    #[test]
+    fn test_merge_definitions() {
+        assert_eq!(merge_definitions(String::from("fashion, fashionable"), String::from("Fashion")), "fashion, fashionable");
+        assert_eq!(merge_definitions(String::from("fashion, fashionable"), String::from("stylish")), "fashion, fashionable<br>stylish");
+    }
+
+    #[test]
+    fn test_build_reading_both() {
+        let token = &tokenise_sentence("時尚")[0];
+        assert_eq!(token.build_reading(&MandarinReading::Both).unwrap(), "ㄕˊ,ㄕㄤˋ shí shàng");
+        assert_eq!(build_measure_word_string("張", &MandarinReading::Both), "張 (ㄓㄤ zhāng)");
+    }
+
+    #[test]
+    fn test_word_reading_field() {
+        //A word card's Reading field is in the configured reading, rather than always zhuyin
+        let token = &tokenise_sentence("好")[0];
+        assert_eq!(token.build_reading(&MandarinReading::Zhuyin).unwrap(), "ㄏㄠˇ,ㄏㄠˋ");
+        assert_eq!(token.build_reading(&MandarinReading::Pinyin).unwrap(), "hǎo,hào");
+    }
+
+    #[test]
+    fn test_build_token_report() {
+        let report = build_token_report(&tokenise_sentence("我喜歡 Rust"), &MandarinReading::Pinyin);
+        assert!(report[0].starts_with("我\tmatched\twǒ\t"));
+        assert_eq!(report[2], " \tnot mandarin\t\t");
+        assert_eq!(report.len(), 7);
+    }
+
+    #[test]
+    fn test_escape_html() {
+        assert_eq!(escape_html("a <b> & \"c\""), "a &lt;b&gt; &amp; &quot;c&quot;");
+        let similar_word = SimilarWord { word: String::from("平反"), translation: String::from("to rehabilitate <someone>") };
+        assert!(similar_word.build_string("píng fǎn", ", ").ends_with(", to rehabilitate &lt;someone&gt;"));
+    }
+
+    #[test]
+    fn test_literal_asterisk() {
+        let build_note_sentence = |hanzi: &str, delimiter: &str| {
+            let hanzi = normalise_highlights(hanzi, delimiter);
+            let sentence = MandarinSentence { raw_sentence: hanzi.clone(), tokens: tokenise_sentence(&hanzi) };
+            build_sentence_fields(sentence.build_note_sentence(), String::new(), None, String::new(), String::new(), String::new(), None)[1].clone()
+        };
+        assert_eq!(build_note_sentence("這個\\*是星號", "*"), "這個*是星號");
+        assert_eq!(build_note_sentence("*這個*是星號", "*"), "<span class=starred>這個</span>是星號");
+        assert_eq!(build_note_sentence("==這個==是*號", "=="), "<span class=starred>這個</span>是*號");
+        assert_eq!(build_note_sentence("\\==這個是*號", "=="), "==這個是*號");
+        assert_eq!(normalise_highlights("**這個**", "**"), "*這個*");
+        assert_eq!(restore_literal_asterisks(&normalise_highlights("\\**這個**", "**")), "**這個*");
+    }
+
+    #[test]
+    fn test_blank_rows() {
+        let mut input_csv_reader = csv::ReaderBuilder::new()
+            .flexible(true)
+            .has_headers(false)
+            .trim(csv::Trim::All)
+            .from_reader("\"   \",Hello\n，。,Hello\n**,\n你好,Hello\n".as_bytes());
+        let blank_rows = input_csv_reader.records()
+            .map(|row| ColumnConfig::default().read_row(&row.unwrap()).unwrap().hanzi)
+            .map(|hanzi| is_blank_hanzi(&hanzi))
+            .collect_vec();
+        assert_eq!(blank_rows, vec![true, true, true, false]);
+        assert!(!is_blank_hanzi("Wi-Fi"));
+    }
+
+    #[test]
+    fn test_missing_words() {
+        let tokens = [
+            Token { text: "時尚".to_string(), word_entry: Some(query_by_chinese("時尚")) },
+            Token { text: "㒯".to_string(), word_entry: Some(Vec::new()) },
+            Token { text: "，".to_string(), word_entry: Some(Vec::new()) },
+            Token { text: "A".to_string(), word_entry: None },
+            Token { text: "㒯".to_string(), word_entry: Some(Vec::new()) },
+        ];
+        let missing_words = find_missing_words(tokens.iter());
+        assert_eq!(missing_words, vec!["㒯"]);
+        let tempdir = tempfile::Builder::new().prefix("test_missing_words").tempdir().unwrap();
+        let missing_words_file = tempdir.path().join("missing_words.csv");
+        write_missing_words(&missing_words_file, &missing_words).unwrap();
+        assert_eq!(std::fs::read_to_string(&missing_words_file).unwrap(), "㒯\n");
+    }
+
+    #[test]
+    fn test_cost_estimate() {
+        let mut config = build_config(Some(Path::new("example_config.yml")), ::config::Environment::default().source(Some(HashMap::new()))).unwrap();
+        let mut cost_estimate = CostEstimate::default();
+        cost_estimate.add_row(&tokenise_sentence("時尚"), false, &config, None);
+        cost_estimate.add_row(&tokenise_sentence("你今天看起來很*時尚*"), false, &config, None);
+        cost_estimate.add_row(&tokenise_sentence("你好嗎"), true, &config, None);
+        assert_eq!(cost_estimate, CostEstimate { rows: 3, tts_characters: 2 + 9 + 3, translation_characters: 9, openai_requests: 1 });
+        let pricing_config = PricingConfig { tts_per_million_characters: 1_000_000.0, translation_per_million_characters: 0.0, openai_per_request: 10.0, confirm_above: 0.0 };
+        assert_eq!(cost_estimate.total(&pricing_config), 24.0);
+        assert_eq!(cost_estimate.build_report(&pricing_config).last().unwrap(), "Estimated cost: 24.00");
+        config.cards.audio = false;
+        config.openai = None;
+        let mut cost_estimate = CostEstimate::default();
+        cost_estimate.add_row(&tokenise_sentence("時尚"), false, &config, None);
+        assert_eq!(cost_estimate.total(&PricingConfig::default()), 0.0);
+        let glossary = HashMap::from([(String::from("龍捲風"), String::from("tornado"))]);
+        config.definition_strategy = DefinitionStrategy::Merge;
+        let mut cost_estimate = CostEstimate::default();
+        cost_estimate.add_row(&tokenise_sentence("龍捲風"), false, &config, Some(&glossary));
+        assert_eq!(cost_estimate.translation_characters, 0);
+        cost_estimate.add_row(&tokenise_sentence("時尚"), false, &config, Some(&glossary));
+        assert_eq!(cost_estimate.translation_characters, 2);
+    }
+
+    #[test]
+    fn test_toneless_reading() {
+        let tokens = tokenise_sentence("時尚");
+        assert_eq!(tokens[0].build_toneless_reading(&MandarinReading::Pinyin).unwrap(), "shi shang");
+        assert_eq!(tokens[0].build_toneless_reading(&MandarinReading::Zhuyin).unwrap(), "ㄕ,ㄕㄤ");
+        assert_eq!(remove_tone_numbers("lu:4 se4"), "lü se");
+        assert_eq!(remove_zhuyin_tones("ㄇㄚ,ㄇㄚ˙"), "ㄇㄚ,ㄇㄚ");
+        assert!(tokenise_sentence("，")[0].build_toneless_reading(&MandarinReading::Pinyin).is_none());
+    }
+
+    #[test]
+    fn test_reading_consistency() {
+        for reading in [MandarinReading::Zhuyin, MandarinReading::Pinyin, MandarinReading::PinyinNumbers, MandarinReading::Both] {
+            for word in ["時尚", "蘋果", "學習"] {
+                let token_reading = tokenise_sentence(word)[0].build_reading(&reading).unwrap();
+                let similar_word = SimilarWord { word: word.to_string(), translation: String::new() };
+                assert_eq!(similar_word.build_dictionary_reading(&reading).unwrap(), token_reading, "{} {:?}", word, reading);
+                let sentence = MandarinSentence { raw_sentence: format!("我喜歡{}", word), tokens: tokenise_sentence(&format!("我喜歡{}", word)) };
+                assert!(sentence.build_word_gloss(&reading).contains(&format!("<td>{}</td><td>{}</td>", word, token_reading)), "{} {:?}", word, reading);
+                //Ruby annotates each character with its own syllable, which read together are the word's reading
+                let ruby = tokenise_sentence(word)[0].build_ruby(&reading).unwrap();
+                let annotations = ruby.split("<rt>").skip(1).map(|annotation| annotation.split("</rt>").next().unwrap()).collect_vec();
+                match reading {
+                    MandarinReading::Zhuyin => assert_eq!(annotations.join(","), token_reading),
+                    MandarinReading::Both => assert_eq!(annotations.len(), word.chars().count()),
+                    _ => assert_eq!(annotations.join(" "), token_reading),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,283 @@
+use itertools::Itertools;
+
+const IPA_INITIALS: &[(&str, &str)] = &[
+    ("zh", "ʈʂ"), ("ch", "ʈʂʰ"), ("sh", "ʂ"), ("b", "p"), ("p", "pʰ"), ("m", "m"), ("f", "f"), ("d", "t"), ("t", "tʰ"), ("n", "n"), ("l", "l"),
+    ("g", "k"), ("k", "kʰ"), ("h", "x"), ("j", "tɕ"), ("q", "tɕʰ"), ("x", "ɕ"), ("r", "ʐ"), ("z", "ts"), ("c", "tsʰ"), ("s", "s"),
+];
+
+const IPA_FINALS: &[(&str, &str)] = &[
+    ("a", "a"), ("o", "o"), ("e", "ɤ"), ("ê", "ɛ"), ("ai", "ai"), ("ei", "ei"), ("ao", "au"), ("ou", "ou"), ("an", "an"), ("en", "ən"),
+    ("ang", "aŋ"), ("eng", "əŋ"), ("ong", "ʊŋ"), ("er", "aɚ"), ("i", "i"), ("ia", "ja"), ("ie", "je"), ("iao", "jau"), ("iou", "jou"),
+    ("ian", "jɛn"), ("in", "in"), ("iang", "jaŋ"), ("ing", "iŋ"), ("iong", "jʊŋ"), ("u", "u"), ("ua", "wa"), ("uo", "wo"), ("uai", "wai"),
+    ("uei", "wei"), ("uan", "wan"), ("uen", "wən"), ("uang", "waŋ"), ("ueng", "wəŋ"), ("ü", "y"), ("üe", "ɥe"), ("üan", "ɥɛn"), ("ün", "yn"),
+];
+
+//Pinyin spells some finals differently depending on the initial, so they're rewritten to their full form before mapping
+const PINYIN_SPELLINGS: &[(&str, &str)] = &[
+    ("yi", "i"), ("ya", "ia"), ("ye", "ie"), ("yao", "iao"), ("you", "iou"), ("yan", "ian"), ("yin", "in"), ("yang", "iang"), ("ying", "ing"),
+    ("yong", "iong"), ("yu", "ü"), ("yue", "üe"), ("yuan", "üan"), ("yun", "ün"), ("wu", "u"), ("wa", "ua"), ("wo", "uo"), ("wai", "uai"),
+    ("wei", "uei"), ("wan", "uan"), ("wen", "uen"), ("wang", "uang"), ("weng", "ueng"),
+];
+
+const IPA_TONES: [&str; 6] = ["", "˥", "˧˥", "˨˩˦", "˥˩", ""];
+
+fn pinyin_syllable_to_ipa(pinyin_syllable: &str) -> Option<String> { //A numbered pinyin syllable like "zhong1"
+    let pinyin_syllable = pinyin_syllable.to_lowercase().replace("u:", "ü").replace('v', "ü");
+    let (syllable, tone) = match pinyin_syllable.char_indices().last()? {
+        (index, tone) if tone.is_ascii_digit() => (&pinyin_syllable[..index], tone.to_digit(10)? as usize),
+        _ => (pinyin_syllable.as_str(), 5),
+    };
+    let tone = IPA_TONES.get(tone)?;
+    if syllable == "r" { //Erhua
+        return Some(String::from("ɚ"));
+    }
+    let (initial, final_) = match PINYIN_SPELLINGS.iter().find(|(spelling, _)| *spelling == syllable) {
+        Some((_, final_)) => ("", final_.to_string()),
+        None => {
+            let (pinyin_initial, ipa_initial) = IPA_INITIALS.iter().find(|(initial, _)| syllable.starts_with(initial)).copied().unwrap_or(("", ""));
+            let final_ = &syllable[pinyin_initial.len()..];
+            let final_ = match (pinyin_initial, final_) {
+                ("zh" | "ch" | "sh" | "r", "i") => return Some(format!("{}ɻ̩{}", ipa_initial, tone)),
+                ("z" | "c" | "s", "i") => return Some(format!("{}ɹ̩{}", ipa_initial, tone)),
+                ("j" | "q" | "x", final_) if final_.starts_with('u') => final_.replacen('u', "ü", 1),
+                (_, "iu") => String::from("iou"),
+                (_, "ui") => String::from("uei"),
+                (_, "un") => String::from("uen"),
+                (_, final_) => final_.to_string(),
+            };
+            (ipa_initial, final_)
+        },
+    };
+    let ipa_final = IPA_FINALS.iter().find(|(pinyin_final, _)| *pinyin_final == final_)?.1;
+    Some(format!("{}{}{}", initial, ipa_final, tone))
+}
+
+//The dictionary writes ü as u:, e.g. lu:4 becomes lü
+pub(crate) fn remove_tone_numbers(pinyin_numbers: &str) -> String {
+    pinyin_numbers.replace("u:", "ü").chars().filter(|char| !char.is_ascii_digit()).collect()
+}
+
+pub(crate) fn remove_zhuyin_tones(zhuyin: &str) -> String {
+    zhuyin.chars().filter(|char| !['ˊ', 'ˇ', 'ˋ', '˙'].contains(char)).collect()
+}
+
+pub(crate) fn pinyin_numbers_to_ipa(pinyin_numbers: &str) -> Option<String> {
+    pinyin_numbers.split_whitespace().map(pinyin_syllable_to_ipa).collect::<Option<Vec<String>>>().map(|syllables| syllables.join(" "))
+}
+
+pub(crate) fn convert_pinyin_numbers_to_marks(syllable: &str) -> String {
+    let Some(tone) = syllable.chars().last().and_then(|tone| tone.to_digit(10)) else {
+        return syllable.to_string();
+    };
+    let syllable = syllable[..syllable.len() - 1].replace('v', "ü");
+    if !(1..=4).contains(&tone) {
+        return syllable;
+    }
+    //The mark goes on a or e if present, the o of ou, otherwise the last vowel
+    let lowercase_syllable = syllable.to_lowercase();
+    let mark_index = lowercase_syllable.find('a')
+        .or_else(|| lowercase_syllable.find('e'))
+        .or_else(|| lowercase_syllable.find("ou"))
+        .or_else(|| lowercase_syllable.rfind(['i', 'o', 'u', 'ü']));
+    let Some(mark_index) = mark_index else {
+        return syllable;
+    };
+    let vowel = syllable[mark_index..].chars().next().unwrap();
+    let tone_index = tone as usize - 1;
+    let marked_vowel = match vowel {
+        'a' => ['ā', 'á', 'ǎ', 'à'][tone_index],
+        'A' => ['Ā', 'Á', 'Ǎ', 'À'][tone_index],
+        'e' => ['ē', 'é', 'ě', 'è'][tone_index],
+        'E' => ['Ē', 'É', 'Ě', 'È'][tone_index],
+        'i' => ['ī', 'í', 'ǐ', 'ì'][tone_index],
+        'o' => ['ō', 'ó', 'ǒ', 'ò'][tone_index],
+        'O' => ['Ō', 'Ó', 'Ǒ', 'Ò'][tone_index],
+        'u' => ['ū', 'ú', 'ǔ', 'ù'][tone_index],
+        'ü' => ['ǖ', 'ǘ', 'ǚ', 'ǜ'][tone_index],
+        _ => vowel,
+    };
+    let vowel_length = vowel.len_utf8();
+    format!("{}{}{}", &syllable[..mark_index], marked_vowel, &syllable[mark_index + vowel_length..])
+}
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+#[error("Unable to parse pinyin syllable: {syllable}")]
+pub struct PinyinParseError {
+    syllable: String,
+}
+
+const PINYIN_TONE_LETTERS: &str = "āáǎàēéěèêīíǐìōóǒòūúǔùüǖǘǚǜĀÁǍÀĒÉĚÈÊĪÍǏÌŌÓǑÒŪÚǓÙÜǕǗǙǛ";
+
+fn is_pinyin_letter(char: char) -> bool {
+    char.is_ascii_alphabetic() || PINYIN_TONE_LETTERS.contains(char)
+}
+
+//Syllables without an initial are written with y or w unless they start with a, o or e.
+fn pinyin_syllable_to_zhuyin(syllable: &str) -> Option<String> {
+    match syllable.chars().next() {
+        Some(first) if "iīíǐìuūúǔùüǖǘǚǜ".contains(first) => None,
+        _ => pinyin_zhuyin::pinyin_to_zhuyin(syllable),
+    }
+}
+
+//Splits a run of pinyin letters into syllables, trying the longest syllable first and
+//backtracking so that words like "qùnián" aren't read as "qùn" + "ián".
+fn segment_pinyin_word(word: &[char]) -> Option<Vec<String>> {
+    if word.is_empty() {
+        return Some(Vec::new());
+    }
+    for length in (1..=word.len().min(6)).rev() {
+        let syllable = word[..length].iter().collect::<String>();
+        if pinyin_syllable_to_zhuyin(&syllable).is_some() {
+            if let Some(mut rest) = segment_pinyin_word(&word[length..]) {
+                rest.insert(0, syllable);
+                return Some(rest);
+            }
+        }
+    }
+    None
+}
+
+//Finds the part of a word that couldn't be segmented by following the longest valid syllables.
+fn find_unparseable_syllable(word: &[char]) -> String {
+    let mut start = 0;
+    while let Some(length) = (1..=(word.len() - start).min(6)).rev()
+        .find(|length| pinyin_syllable_to_zhuyin(&word[start..start + length].iter().collect::<String>()).is_some()) {
+        start += length;
+        if start == word.len() {
+            return word.iter().collect();
+        }
+    }
+    word[start..].iter().collect()
+}
+
+//Azure leaves Latin text like Python or OK as it is, so a word without tone marks which can't be segmented is kept as is.
+//Only a word with tone marks is certainly pinyin, so only that is an error
+fn convert_pinyin_word(word: &[char]) -> Result<String, PinyinParseError> {
+    let lowercase_word = word.iter().flat_map(|char| char.to_lowercase()).collect::<Vec<char>>();
+    match segment_pinyin_word(&lowercase_word) {
+        Some(syllables) => Ok(syllables.iter().filter_map(|syllable| pinyin_syllable_to_zhuyin(syllable)).collect::<String>()),
+        None if word.iter().all(char::is_ascii) => Ok(word.iter().collect()),
+        None => Err(PinyinParseError { syllable: find_unparseable_syllable(&lowercase_word) }),
+    }
+}
+
+//Words are separated by commas in the zhuyin reading, apostrophes only separate syllables and
+//any other punctuation (including the * highlighting) is kept as is.
+pub fn convert_pinyin_to_zhuyin(pinyin_reading: &str) -> Result<String, PinyinParseError> {
+    let mut zhuyin_reading = String::new();
+    let mut word = Vec::new();
+    for char in pinyin_reading.chars() {
+        if is_pinyin_letter(char) {
+            word.push(char);
+            continue;
+        }
+        if !word.is_empty() {
+            zhuyin_reading.push_str(&convert_pinyin_word(&word)?);
+            word.clear();
+        }
+        match char {
+            ' ' => zhuyin_reading.push(','),
+            '\'' | '’' => (),
+            _ => zhuyin_reading.push(char),
+        }
+    }
+    if !word.is_empty() {
+        zhuyin_reading.push_str(&convert_pinyin_word(&word)?);
+    }
+    Ok(zhuyin_reading.replace("，,", "，"))
+}
+
+const PINYIN_TONE_MARKS: &[(char, &str)] = &[('a', "āáǎà"), ('e', "ēéěè"), ('i', "īíǐì"), ('o', "ōóǒò"), ('u', "ūúǔù"), ('ü', "ǖǘǚǜ")];
+
+fn convert_pinyin_syllable_to_numbers(syllable: &str) -> String { //Unmarked syllables are the neutral tone, and ü is typed as v
+    let mut tone = 5;
+    let plain_syllable = syllable.chars().map(|char| {
+        match PINYIN_TONE_MARKS.iter().find_map(|(vowel, marks)| marks.chars().position(|mark| mark == char).map(|index| (*vowel, index + 1))) {
+            Some((vowel, mark_tone)) => {
+                tone = mark_tone;
+                vowel
+            },
+            None => char,
+        }
+    }).collect::<String>();
+    format!("{}{}", plain_syllable.replace('ü', "v"), tone)
+}
+
+fn convert_pinyin_word_to_numbers(word: &[char]) -> String { //A word which can't be segmented is kept as is
+    let lowercase_word = word.iter().flat_map(|char| char.to_lowercase()).collect::<Vec<char>>();
+    match segment_pinyin_word(&lowercase_word) {
+        Some(syllables) => syllables.iter().map(|syllable| convert_pinyin_syllable_to_numbers(syllable)).join(" "),
+        None => word.iter().collect(),
+    }
+}
+
+//Syllables are separated by spaces, and any text which isn't pinyin is kept as is
+pub fn convert_pinyin_marks_to_numbers(pinyin_reading: &str) -> String {
+    let mut numbers_reading = String::new();
+    let mut word = Vec::new();
+    for char in pinyin_reading.chars() {
+        if is_pinyin_letter(char) {
+            word.push(char);
+            continue;
+        }
+        if !word.is_empty() {
+            numbers_reading.push_str(&convert_pinyin_word_to_numbers(&word));
+            word.clear();
+        }
+        match char {
+            '\'' | '’' => numbers_reading.push(' '),
+            _ => numbers_reading.push(char),
+        }
+    }
+    if !word.is_empty() {
+        numbers_reading.push_str(&convert_pinyin_word_to_numbers(&word));
+    }
+    numbers_reading
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tokenise_sentence, config::MandarinReading};
+
+    #[test]
+    fn test_convert_pinyin_numbers_to_marks() {
+        assert_eq!(convert_pinyin_numbers_to_marks("hao3"), "hǎo");
+        assert_eq!(convert_pinyin_numbers_to_marks("dou1"), "dōu");
+        assert_eq!(convert_pinyin_numbers_to_marks("gui4"), "guì");
+        assert_eq!(convert_pinyin_numbers_to_marks("Zhang1"), "Zhāng");
+        assert_eq!(convert_pinyin_numbers_to_marks("ma5"), "ma");
+        assert_eq!(convert_pinyin_numbers_to_marks("nv3"), "nǚ");
+    }
+
+    #[test]
+    fn test_convert_pinyin_to_zhuyin() {
+        assert_eq!(convert_pinyin_to_zhuyin("Dōu shì *māma* tài chǒng tā ， cái huì").unwrap(), "ㄉㄡ,ㄕˋ,*ㄇㄚㄇㄚ˙*,ㄊㄞˋ,ㄔㄨㄥˇ,ㄊㄚ,，ㄘㄞˊ,ㄏㄨㄟˋ");
+        //A word ending in u followed by a syllable starting with n, without an apostrophe
+        assert_eq!(convert_pinyin_to_zhuyin("qùnián").unwrap(), "ㄑㄩˋㄋㄧㄢˊ");
+        assert_eq!(convert_pinyin_to_zhuyin("xī'ān").unwrap(), "ㄒㄧㄢ");
+        assert_eq!(convert_pinyin_to_zhuyin("hǎo xyz").unwrap(), "ㄏㄠˇ,xyz");
+        assert_eq!(convert_pinyin_to_zhuyin("wǒ huì Python ， OK").unwrap(), "ㄨㄛˇ,ㄏㄨㄟˋ,Python,，OK");
+        assert_eq!(convert_pinyin_to_zhuyin("hǎoxyz"), Err(PinyinParseError { syllable: String::from("xyz") }));
+    }
+
+    #[test]
+    fn test_convert_pinyin_marks_to_numbers() {
+        assert_eq!(convert_pinyin_marks_to_numbers("dōu shì yīnwèi māma tài *chǒng* tā，cái"), "dou1 shi4 yin1 wei4 ma1 ma5 tai4 *chong3* ta1，cai2");
+        assert_eq!(convert_pinyin_marks_to_numbers("nǚ'ér Qùnián"), "nv3 er2 qu4 nian2");
+        assert_eq!(convert_pinyin_marks_to_numbers("Rust"), "Rust");
+        let token = &tokenise_sentence("時尚")[0];
+        assert_eq!(token.build_reading(&MandarinReading::PinyinNumbers).unwrap(), "shi2 shang4");
+    }
+
+    #[test]
+    fn test_pinyin_numbers_to_ipa() {
+        assert_eq!(pinyin_numbers_to_ipa("ni3 hao3").unwrap(), "ni˨˩˦ xau˨˩˦");
+        assert_eq!(pinyin_numbers_to_ipa("Zhong1 guo2").unwrap(), "ʈʂʊŋ˥ kwo˧˥");
+        assert_eq!(pinyin_numbers_to_ipa("xue2 sheng5").unwrap(), "ɕɥe˧˥ ʂəŋ");
+        assert_eq!(pinyin_numbers_to_ipa("lu:4 shi1 zi4 you3 wei4").unwrap(), "ly˥˩ ʂɻ̩˥ tsɹ̩˥˩ jou˨˩˦ wei˥˩");
+        assert!(pinyin_numbers_to_ipa("xx1").is_none());
+        assert_eq!(tokenise_sentence("時尚")[0].build_ipa().unwrap(), "ʂɻ̩˧˥ ʂaŋ˥˩");
+    }
+}
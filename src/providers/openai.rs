@@ -0,0 +1,309 @@
+use std::sync::Arc;
+use chinese_dictionary::{WordEntry, ClassificationResult, classify};
+use futures::FutureExt;
+use itertools::Itertools;
+use log::{info, warn, debug, trace};
+use reqwest::{Client, header::{HeaderMap, CONTENT_TYPE, AUTHORIZATION, HeaderValue, HeaderName}};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tokio::sync::{OnceCell, Mutex};
+use crate::{Error, escape_html, tokenise_sentence, config::{GenankiConfig, MandarinReading, MandarinScript, OpenAIConfig}, dictionary::query_by_chinese, pinyin::convert_pinyin_marks_to_numbers, providers::{RateLimiter, honour_retry_after, retry_policy}, providers::translation::get_transliteration};
+
+pub(crate) static OPENAI_RATE_LIMITER: OnceCell<RateLimiter> = OnceCell::const_new();
+const SIMILAR_WORDS_COUNT: usize = 5;
+
+//Prompts can contain {script} and {count} placeholders
+fn fill_prompt_template(template: &str, script: &MandarinScript, count: usize) -> String {
+    template.replace("{script}", &script.to_string()).replace("{count}", &count.to_string())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SimilarWord {
+    pub word: String,
+    pub translation: String
+}
+
+impl SimilarWord {
+    //From an entry for the whole word, or else from the entries of each word it tokenises into.
+    //None if any part of it isn't in the dictionary, rather than guessing from a partial match
+    pub(crate) fn build_dictionary_reading(&self, reading: &MandarinReading) -> Option<String> {
+        let is_exact_entry = |entry: &&WordEntry, text: &str| entry.traditional == text || entry.simplified == text;
+        let entries = match query_by_chinese(&self.word).into_iter().find(|entry| is_exact_entry(entry, &self.word)) {
+            Some(entry) => vec![entry],
+            None => tokenise_sentence(&self.word).iter()
+                .map(|token| token.word_entry.as_ref()?.iter().find(|entry| is_exact_entry(entry, &token.text)).copied())
+                .collect::<Option<Vec<&WordEntry>>>()?,
+        };
+        if entries.iter().map(|entry| entry.traditional.chars().count()).sum::<usize>() != self.word.chars().count() {
+            return None;
+        }
+        Some(reading.build_entries_reading(&entries))
+    }
+    pub(crate) async fn build_reading(&self, client: &Client, genanki_config: &GenankiConfig, mutex: Arc<Mutex<i32>>) -> Result<String, Error> { //Falls back to Azure when the dictionary can't cover the word
+        match self.build_dictionary_reading(&genanki_config.mandarin.reading) {
+            Some(reading_str) => Ok(reading_str),
+            None => {
+                info!("Similar word {} isn't cleanly in the dictionary, transliterating it instead", self.word);
+                let (pinyin_reading, zhuyin_reading) = get_transliteration(&self.word, client, genanki_config, mutex).await?;
+                Ok(genanki_config.mandarin.reading.build_reading_string(|| zhuyin_reading, || pinyin_reading.clone(), || convert_pinyin_marks_to_numbers(&pinyin_reading)))
+            },
+        }
+    }
+    pub(crate) fn build_string(&self, reading_str: &str, separator: &str) -> String {
+        let mut output = escape_html(&self.word);
+        output.push_str(separator);
+        output.push_str(reading_str);
+        output.push_str(separator);
+        output.push_str(&escape_html(&self.translation));
+        output
+    }
+}
+
+pub(crate) fn build_openai_headers(openai_config: &OpenAIConfig) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_str("application/json").unwrap());
+    headers.insert(AUTHORIZATION, HeaderValue::from_str(&format!("Bearer {}", openai_config.key)).unwrap());
+    if let Some(organisation) = &openai_config.organisation {
+        headers.insert(HeaderName::from_lowercase(b"openai-organization").unwrap(), HeaderValue::from_str(organisation).unwrap());
+    }
+    headers
+}
+
+pub async fn get_similar_words(word: &str, client: &Client, openai_config: &OpenAIConfig, script: &MandarinScript) -> Result<Vec<SimilarWord>, Error> {
+
+    let mut messages = vec![
+        json!({
+            "role": "system",
+            "content": fill_prompt_template(&openai_config.system_prompt, script, SIMILAR_WORDS_COUNT)
+        }),
+        json!({
+            "role": "user",
+            "content": format!("Generate {} words closely related to {} {}.
+                                You should provide the words in {} and the English Translation in CSV format with two columns.",
+                            SIMILAR_WORDS_COUNT, word, fill_prompt_template(&openai_config.locale_clause, script, SIMILAR_WORDS_COUNT), script)
+        }),
+    ];
+    let message = get_chat_completion(&messages, client, openai_config).await?;
+    let mut similar_words = parse_similar_words(&message, word);
+
+    if similar_words.is_empty() {
+        warn!("No similar words could be parsed for {}, OpenAI responded with: {}", word, message);
+        if openai_config.retry_malformed_response {
+            messages.push(json!({"role": "assistant", "content": message}));
+            messages.push(json!({"role": "user", "content": "Respond ONLY with CSV, with no other text. Each row should be a word followed by a comma and its English Translation."}));
+            let message = get_chat_completion(&messages, client, openai_config).await?;
+            similar_words = parse_similar_words(&message, word);
+            if similar_words.is_empty() {
+                warn!("Still no similar words could be parsed for {} after retrying, OpenAI responded with: {}", word, message);
+            }
+        }
+    }
+    debug!("Similar Words Parsed: {:#?}", similar_words);
+
+    Ok(similar_words)
+}
+
+async fn get_chat_completion(messages: &[Value], client: &Client, openai_config: &OpenAIConfig) -> Result<String, Error> {
+    let headers = build_openai_headers(openai_config);
+    let res = retry_policy().retry(|| async {
+        if let Some(rate_limiter) = OPENAI_RATE_LIMITER.get() {
+            rate_limiter.acquire().await;
+        }
+        client.post("https://api.openai.com/v1/chat/completions")
+            .headers(headers.clone())
+            .json(&build_chat_completion_body(messages, openai_config))
+            .send()
+            .then(honour_retry_after)
+            .await
+        })
+        .await?;
+    trace!("OpenAI Response: {:#?}", res);
+
+    let json = res.json::<Value>().await?;
+    debug!("Json From OpenAI: {:#?}", json);
+
+    json["choices"][0]["message"]["content"].as_str()
+        .map(|message| message.to_string())
+        .ok_or_else(|| Error::Parse(format!("OpenAI returned no message: {}", json)))
+}
+
+fn build_chat_completion_body(messages: &[Value], openai_config: &OpenAIConfig) -> Value {
+    let mut body = json!({
+        "model": "gpt-3.5-turbo",
+        "messages": messages
+    });
+    //Left out when not set so OpenAI's own defaults apply
+    if let Some(max_tokens) = openai_config.max_tokens {
+        body["max_tokens"] = json!(max_tokens);
+    }
+    if let Some(temperature) = openai_config.temperature {
+        body["temperature"] = json!(temperature);
+    }
+    body
+}
+
+pub(crate) async fn get_measure_word(word: &str, client: &Client, openai_config: &OpenAIConfig, script: &MandarinScript) -> Result<Option<String>, Error> {
+    let messages = vec![
+        json!({
+            "role": "system",
+            "content": fill_prompt_template(&openai_config.system_prompt, script, 1)
+        }),
+        json!({
+            "role": "user",
+            "content": format!("If {} is a noun, respond with only the measure word most commonly used with it in {}, and nothing else.
+                                If it is not a noun, respond with only NONE.",
+                            word, script)
+        }),
+    ];
+    let message = get_chat_completion(&messages, client, openai_config).await?;
+    let measure_word = parse_measure_word(&message);
+    debug!("Measure Word Parsed: {:#?}", measure_word);
+    Ok(measure_word)
+}
+
+pub(crate) async fn get_example_sentence(word: &str, client: &Client, openai_config: &OpenAIConfig, script: &MandarinScript) -> Result<Option<String>, Error> {
+    let messages = vec![
+        json!({
+            "role": "system",
+            "content": fill_prompt_template(&openai_config.system_prompt, script, 1)
+        }),
+        json!({
+            "role": "user",
+            "content": format!("Write one short, natural example sentence in {} using {} {}. Respond with only the sentence, and nothing else.",
+                            script, word, fill_prompt_template(&openai_config.locale_clause, script, 1))
+        }),
+    ];
+    let message = get_chat_completion(&messages, client, openai_config).await?;
+    let example_sentence = parse_example_sentence(&message, word);
+    if example_sentence.is_none() {
+        warn!("No example sentence could be parsed for {}, OpenAI responded with: {}", word, message);
+    }
+    Ok(example_sentence)
+}
+
+fn parse_example_sentence(message: &str, word: &str) -> Option<String> { //The first line, as long as it actually uses the word
+    let example_sentence = message.lines().map(|line| line.trim().trim_matches(['"', '“', '”', '「', '」'])).find(|line| !line.is_empty())?;
+    match example_sentence.contains(word) {
+        true => Some(example_sentence.to_string()),
+        false => None,
+    }
+}
+
+fn parse_measure_word(message: &str) -> Option<String> { //Measure words are one or two characters, anything else is NONE or a malformed response
+    let measure_word = message.trim().trim_end_matches(['。', '.']);
+    match classify(measure_word) == ClassificationResult::ZH && (1..=2).contains(&measure_word.chars().count()) {
+        true => Some(measure_word.to_string()),
+        false => None,
+    }
+}
+
+pub(crate) fn build_measure_word_string(measure_word: &str, reading: &MandarinReading) -> String {
+    let measure_word_entry = query_by_chinese(measure_word).into_iter()
+        .filter(|word| word.traditional == measure_word || word.simplified == measure_word)
+        .min_by_key(|word| word.pinyin_numbers.starts_with(char::is_uppercase)); //Avoid surname entries
+    match measure_word_entry {
+        Some(word) => {
+            let reading_str = reading.build_entries_reading(&[word]);
+            format!("{} ({})", measure_word, reading_str)
+        },
+        None => measure_word.to_string(),
+    }
+}
+
+fn parse_similar_words(message: &str, word: &str) -> Vec<SimilarWord> {
+    let rows = message.split('\n').map(|row| row.split(',').collect_vec()).collect_vec();
+
+    let mut similar_words: Vec<SimilarWord> = Vec::new();
+
+    for row in rows {
+        if row.len() >= 2 && classify(row[0]) == ClassificationResult::ZH { //Rows with actual csv content
+            if is_same_word(row[0], word) {
+                debug!("Skipping similar word {} as it is the word itself", row[0]);
+                continue;
+            }
+            let similar_word = SimilarWord { word: row[0].trim().to_string(), translation: row[1].trim().to_string() };
+            similar_words.push(similar_word);
+        }
+    }
+    similar_words
+}
+
+fn is_same_word(similar_word: &str, word: &str) -> bool { //Also matches the word written in the other script
+    let similar_word = similar_word.trim();
+    similar_word == word.trim() || query_by_chinese(similar_word).iter()
+        .any(|entry| (entry.traditional == similar_word || entry.simplified == similar_word) && (entry.traditional == word || entry.simplified == word))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{default_locale_clause, parse_config};
+
+    #[test]
+    fn test_parse_similar_words() {
+        let similar_words = parse_similar_words("平反,Exoneration\n悔過, Humble repentance\n", "改過");
+        assert_eq!(similar_words.len(), 2);
+        assert_eq!(similar_words[1].word, "悔過");
+        assert_eq!(similar_words[1].translation, "Humble repentance");
+        assert!(parse_similar_words("I'm sorry, but I can't help with that.", "改過").is_empty());
+    }
+
+    #[test]
+    fn test_parse_similar_words_skips_word() {
+        let similar_words = parse_similar_words("時尚,Fashion\n 时尚 ,Fashion\n潮流,Trend\n流行,Popular", "時尚");
+        assert_eq!(similar_words.iter().map(|similar_word| similar_word.word.as_str()).collect_vec(), vec!["潮流", "流行"]);
+    }
+
+    #[test]
+    fn test_parse_measure_word() {
+        assert_eq!(parse_measure_word("隻"), Some(String::from("隻")));
+        assert_eq!(parse_measure_word(" 張。\n"), Some(String::from("張")));
+        assert_eq!(parse_measure_word("NONE"), None);
+        assert_eq!(parse_measure_word("這個詞不是名詞所以沒有量詞"), None);
+        assert_eq!(build_measure_word_string("張", &MandarinReading::Pinyin), "張 (zhāng)");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_get_similar_word() {
+        let client = reqwest::Client::new();
+        let config = parse_config(None).unwrap();
+        let similar_words = get_similar_words("你好", &client, config.openai.as_ref().unwrap(), &config.mandarin.script).await.unwrap();
+        println!("Got Similar Words: {:#?}", similar_words);
+        assert!(!similar_words.is_empty());
+    }
+    #[test]
+    fn test_fill_prompt_template() {
+        let openai_config = serde_json::from_value::<OpenAIConfig>(json!({"key": "key", "system_prompt": "You write {script} study material"})).unwrap();
+        assert_eq!(fill_prompt_template(&openai_config.system_prompt, &MandarinScript::Simplified, 5), "You write Simplified Chinese study material");
+        assert_eq!(fill_prompt_template("Give {count} words", &MandarinScript::Traditional, 5), "Give 5 words");
+        assert_eq!(openai_config.locale_clause, default_locale_clause());
+    }
+
+    #[test]
+    fn test_parse_example_sentence() {
+        assert_eq!(parse_example_sentence("「她今天穿得很時尚。」\n", "時尚"), Some(String::from("她今天穿得很時尚。")));
+        assert_eq!(parse_example_sentence("她今天穿得很时尚。", "時尚"), None);
+        assert_eq!(parse_example_sentence("", "時尚"), None);
+    }
+
+    #[test]
+    fn test_build_similar_word_dictionary_reading() {
+        let similar_word = |word: &str| SimilarWord { word: word.to_string(), translation: String::new() };
+        assert_eq!(similar_word("平反").build_dictionary_reading(&MandarinReading::Pinyin), Some(String::from("píng fǎn")));
+        assert_eq!(similar_word("時尚潮流").build_dictionary_reading(&MandarinReading::Pinyin), Some(String::from("shí shàng cháo liú")));
+        assert_eq!(similar_word("時尚潮流").build_dictionary_reading(&MandarinReading::Zhuyin), Some(String::from("ㄕˊ,ㄕㄤˋ,ㄔㄠˊ,ㄌㄧㄡˊ")));
+        assert_eq!(similar_word("時尚X").build_dictionary_reading(&MandarinReading::Pinyin), None);
+    }
+
+    #[test]
+    fn test_build_chat_completion_body() {
+        let messages = [json!({"role": "user", "content": "你好"})];
+        let openai_config = serde_json::from_value::<OpenAIConfig>(json!({"key": "key"})).unwrap();
+        assert_eq!(build_chat_completion_body(&messages, &openai_config), json!({"model": "gpt-3.5-turbo", "messages": messages}));
+
+        let openai_config = serde_json::from_value::<OpenAIConfig>(json!({"key": "key", "max_tokens": 200, "temperature": 0.2})).unwrap();
+        let body = build_chat_completion_body(&messages, &openai_config);
+        assert_eq!(body["max_tokens"], 200);
+        assert_eq!(body["temperature"], 0.2);
+    }
+}
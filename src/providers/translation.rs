@@ -0,0 +1,267 @@
+use std::{io::IsTerminal, sync::Arc};
+use futures::FutureExt;
+use itertools::Itertools;
+use log::{warn, debug, trace};
+use reqwest::Client;
+use serde_json::{Value, json};
+use tokio::sync::Mutex;
+use crate::{Error, escape_html, cache::{build_cache_key, read_cache, write_cache}, config::{AzureConfig, GenankiConfig, MandarinReading, ZhuyinSource}, pinyin::convert_pinyin_to_zhuyin, providers::{honour_retry_after, retry_policy}};
+
+pub(crate) fn build_translation_cache_key(mandarin_text: &str) -> String {
+    build_cache_key("translation", &[mandarin_text, "en"])
+}
+
+fn read_cached_translation(mandarin_text: &str) -> Option<String> {
+    read_cache(&build_translation_cache_key(mandarin_text)).and_then(|english_text| String::from_utf8(english_text).ok())
+}
+
+pub async fn get_translation(mandarin_text: &str, client: &Client, azure_config: &AzureConfig) -> Result<String, Error> {
+    if let Some(english_text) = read_cached_translation(mandarin_text) {
+        return Ok(english_text);
+    }
+    let res = retry_policy().retry(||
+        client.post(azure_config.translator.build_url("translate?api-version=3.0&to=en"))
+            .header("Ocp-Apim-Subscription-Key", &azure_config.translator.key)
+            .header("Ocp-Apim-Subscription-Region", &azure_config.region)
+            .header("Content-Type", "application/json; charset=UTF-8")
+            .json(&json!([{"text": mandarin_text}]))
+            .send()
+            .then(honour_retry_after)
+        )
+        .await?;
+    trace!("Translation Response: {:#?}", res);
+    
+    let json = res.json::<Value>().await?;
+    let english_text = json[0]["translations"][0]["text"].as_str()
+        .ok_or_else(|| Error::Parse(format!("Azure returned no translation for {}: {}", mandarin_text, json)))?;
+    debug!("English Text from Translation: {}", english_text);
+    let english_text = escape_html(english_text); //Translations go straight into the note's html
+    write_cache(&build_translation_cache_key(mandarin_text), english_text.as_bytes());
+    Ok(english_text)
+}
+
+const TRANSLATION_BATCH_MAX_TEXTS: usize = 100;
+const TRANSLATION_BATCH_MAX_CHARACTERS: usize = 10000;
+
+fn batch_translation_texts(mandarin_texts: &[String]) -> Vec<&[String]> { //Splits the texts into batches which respect the translator's per request limits
+    let mut batches = Vec::new();
+    let mut batch_start = 0;
+    let mut batch_characters = 0;
+    for (index, mandarin_text) in mandarin_texts.iter().enumerate() {
+        let characters = mandarin_text.chars().count();
+        if index > batch_start && (index - batch_start == TRANSLATION_BATCH_MAX_TEXTS || batch_characters + characters > TRANSLATION_BATCH_MAX_CHARACTERS) {
+            batches.push(&mandarin_texts[batch_start..index]);
+            batch_start = index;
+            batch_characters = 0;
+        }
+        batch_characters += characters;
+    }
+    if batch_start < mandarin_texts.len() {
+        batches.push(&mandarin_texts[batch_start..]);
+    }
+    batches
+}
+
+//Only the texts which aren't already cached are sent to the translator
+pub async fn get_translations(mandarin_texts: &[String], client: &Client, azure_config: &AzureConfig) -> Result<Vec<String>, Error> {
+    let cached_english_texts = mandarin_texts.iter().map(|mandarin_text| read_cached_translation(mandarin_text)).collect_vec();
+    let uncached_mandarin_texts = mandarin_texts.iter().zip(&cached_english_texts)
+        .filter(|(_, cached_english_text)| cached_english_text.is_none())
+        .map(|(mandarin_text, _)| mandarin_text.clone())
+        .collect_vec();
+    let requested_english_texts = match uncached_mandarin_texts.is_empty() {
+        true => Vec::new(),
+        false => request_translations(&uncached_mandarin_texts, client, azure_config).await?,
+    };
+    if requested_english_texts.len() != uncached_mandarin_texts.len() {
+        return Err(Error::Parse(format!("Azure returned {} translations for {} texts", requested_english_texts.len(), uncached_mandarin_texts.len())));
+    }
+    let mut requested_english_texts = requested_english_texts.into_iter();
+    Ok(mandarin_texts.iter().zip(cached_english_texts).map(|(mandarin_text, cached_english_text)| match cached_english_text {
+        Some(english_text) => english_text,
+        None => {
+            let english_text = requested_english_texts.next().unwrap();
+            if !english_text.is_empty() { //Otherwise it's requested again next run
+                write_cache(&build_translation_cache_key(mandarin_text), english_text.as_bytes());
+            }
+            english_text
+        },
+    }).collect_vec())
+}
+
+async fn request_translations(mandarin_texts: &[String], client: &Client, azure_config: &AzureConfig) -> Result<Vec<String>, Error> {
+    let mut english_texts = Vec::new();
+    for batch in batch_translation_texts(mandarin_texts) {
+        let body = batch.iter().map(|mandarin_text| json!({"text": mandarin_text})).collect_vec();
+        let res = retry_policy().retry(||
+            client.post(azure_config.translator.build_url("translate?api-version=3.0&to=en"))
+                .header("Ocp-Apim-Subscription-Key", &azure_config.translator.key)
+                .header("Ocp-Apim-Subscription-Region", &azure_config.region)
+                .header("Content-Type", "application/json; charset=UTF-8")
+                .json(&body)
+                .send()
+                .then(honour_retry_after)
+            )
+            .await?;
+        trace!("Batch Translation Response: {:#?}", res);
+
+        let json = res.json::<Value>().await?;
+        let translations = json.as_array().ok_or_else(|| Error::Parse(format!("Azure returned no translations: {}", json)))?;
+        for translation in translations {
+            let english_text = translation["translations"][0]["text"].as_str()
+                .ok_or_else(|| Error::Parse(format!("Azure returned a translation without any text: {}", translation)))?;
+            english_texts.push(escape_html(english_text));
+        }
+    }
+    debug!("English Texts from Batch Translation: {:#?}", english_texts);
+    Ok(english_texts)
+}
+
+pub async fn get_transliteration(mandarin_text: &str, client: &Client, genanki_config: &GenankiConfig, mutex: Arc<Mutex<i32>>) -> Result<(String, String), Error> {
+    let mandarin_config = &genanki_config.mandarin;
+    if mandarin_config.zhuyin_source == ZhuyinSource::Azure {
+        //The pinyin is only needed when it's shown alongside the zhuyin
+        let pinyin_reading = match mandarin_config.reading {
+            MandarinReading::Zhuyin => String::new(),
+            _ => request_transliteration(mandarin_text, "Latn", client, genanki_config).await?,
+        };
+        let zhuyin_reading = request_transliteration(mandarin_text, "Bopo", client, genanki_config).await?;
+        debug!("Zhuyin Reading from Transliteration: {}", zhuyin_reading);
+        return Ok((pinyin_reading, zhuyin_reading));
+    }
+
+    let pinyin_reading = request_transliteration(mandarin_text, "Latn", client, genanki_config).await?;
+    debug!("Pinyin Reading from Transliteration: {}", pinyin_reading);
+    
+    let zhuyin_reading = convert_pinyin_to_zhuyin(&pinyin_reading);
+
+    match zhuyin_reading {
+        Ok(zhuyin_reading) => {
+            debug!("Zhuyin Reading from Pinyin: {}", zhuyin_reading);
+        
+            Ok((pinyin_reading, zhuyin_reading))
+        },
+        //Nobody can type a fix when stdin isn't a terminal (e.g. cron or CI), so the pinyin is used as is
+        Err(error) if !std::io::stdin().is_terminal() => {
+            warn!("{}, using the pinyin as stdin isn't a terminal to ask for a fix: {}", error, pinyin_reading);
+            Ok((pinyin_reading.clone(), pinyin_reading))
+        },
+        Err(error) => {
+            let _lock = mutex.lock().await;
+            let mut rl = rustyline::DefaultEditor::new()?;
+            let line = rl.readline_with_initial (&format!("{}. Please attempt a fix:", error), (&pinyin_reading, ""))?;
+            let zhuyin_reading = convert_pinyin_to_zhuyin(&line);
+            Ok((pinyin_reading.clone(), zhuyin_reading.unwrap_or(pinyin_reading)))
+        }
+    }
+        
+}
+
+//Latn for pinyin or Bopo for zhuyin
+async fn request_transliteration(mandarin_text: &str, to_script: &str, client: &Client, genanki_config: &GenankiConfig) -> Result<String, Error> {
+    let res = retry_policy().retry(||
+        client.post(genanki_config.azure.translator.build_url(&format!("transliterate?api-version=3.0&language={}&fromScript={}&toScript={}", &genanki_config.mandarin.script.build_language(), &genanki_config.mandarin.script.build_from_script(), to_script)))
+            .header("Ocp-Apim-Subscription-Key", &genanki_config.azure.translator.key)
+            .header("Ocp-Apim-Subscription-Region", &genanki_config.azure.region)
+            .header("Content-Type", "application/json; charset=UTF-8")
+            .json(&json!([{"text": mandarin_text}]))
+            .send()
+            .then(honour_retry_after)
+        )
+        .await?;
+    trace!("Transliteration Response: {:#?}", res);
+    
+    let json = res.json::<Value>().await?;
+    debug!("Json From Transliteration: {:#?}", json);
+
+    let transliteration = join_transliteration_segments(&json);
+    if transliteration.is_empty() {
+        warn!("Azure returned no transliteration for {}", mandarin_text);
+    }
+    Ok(transliteration)
+}
+
+//Azure can split a long input across several results, so every segment is kept in order rather than only the first
+fn join_transliteration_segments(json: &Value) -> String {
+    json.as_array().into_iter().flatten()
+        .filter_map(|segment| segment["text"].as_str())
+        .map(|text| text.trim())
+        .filter(|text| !text.is_empty())
+        .join(" ")
+}
+
+#[cfg(test)]
+async fn get_available_transliteration_scripts(client: &Client) {
+    let res = client.get("https://api.cognitive.microsofttranslator.com/languages?api-version=3.0&scope=transliteration")
+        .send()
+        .await
+        .unwrap();
+
+    let json = res.json::<Value>().await.unwrap();
+    println!("{:#?}", json["transliteration"]["zh-Hant"]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::parse_config, providers::spawn_mock_server};
+
+    #[test]
+    fn test_batch_translation_texts() {
+        let mandarin_texts = (0..250).map(|index| index.to_string()).collect_vec();
+        let batches = batch_translation_texts(&mandarin_texts);
+        assert_eq!(batches.iter().map(|batch| batch.len()).collect_vec(), vec![100, 100, 50]);
+        let mandarin_texts = vec!["字".repeat(6000), "字".repeat(6000), String::from("你好")];
+        let batches = batch_translation_texts(&mandarin_texts);
+        assert_eq!(batches.iter().map(|batch| batch.len()).collect_vec(), vec![1, 2]);
+        assert!(batch_translation_texts(&[]).is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_get_available_transliteration_scripts() {
+        let client = reqwest::Client::new();
+        //Just run and check stdout
+        get_available_transliteration_scripts(&client).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_get_translation() {
+        let client = reqwest::Client::new();
+        let translation = get_translation("Hello", &client, &parse_config(None).unwrap().azure).await.unwrap();
+        println!("Got Translation: {translation}");
+        assert!(!translation.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_get_transliteration() {
+        let mutex = Arc::new(Mutex::new(0));
+        let client = reqwest::Client::new();
+        let (pinyin_reading, zhuyin_reading) = get_transliteration("都是因為媽媽太*寵*他，才會這麼軟弱", &client, &parse_config(None).unwrap(), mutex).await.unwrap();
+        println!("Got Pinyin: {pinyin_reading}, Zhuyin: {zhuyin_reading}");
+        assert!(!pinyin_reading.is_empty());
+        assert!(!zhuyin_reading.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_get_translations_count() {
+        //Only one translation comes back for two texts
+        let (endpoint, _) = spawn_mock_server(|_, _| ("200 OK", json!([{"translations": [{"text": "Good night", "to": "en"}]}]).to_string().into_bytes()));
+        let azure_config: AzureConfig = serde_json::from_value(json!({
+            "translator": {"key": "translator key", "endpoint": endpoint},
+            "speech": {"key": "speech key", "locale": "zh-TW"},
+            "region": "uksouth",
+        })).unwrap();
+        let client = reqwest::Client::new();
+        assert_eq!(get_translations(&[String::from("晚安")], &client, &azure_config).await.unwrap(), ["Good night"]);
+        assert!(matches!(get_translations(&[String::from("晚安"), String::from("早安")], &client, &azure_config).await, Err(Error::Parse(_))));
+    }
+
+    #[test]
+    fn test_join_transliteration_segments() {
+        let json = json!([{"text": "nǐ hǎo ma ？", "script": "Latn"}]);
+        assert_eq!(join_transliteration_segments(&json), "nǐ hǎo ma ？");
+        let json = json!([{"text": "wǒ hěn hǎo 。", "script": "Latn"}, {"text": "nǐ ne ？", "script": "Latn"}]);
+        assert_eq!(join_transliteration_segments(&json), "wǒ hěn hǎo 。 nǐ ne ？");
+        assert_eq!(join_transliteration_segments(&json!({"error": {"code": 400000}})), "");
+    }
+}
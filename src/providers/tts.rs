@@ -0,0 +1,621 @@
+use std::{collections::BTreeMap, fs::File, io::Write, path::{Path, PathBuf}, time::Duration, sync::atomic::{AtomicBool, Ordering}};
+use chinese_dictionary::{ClassificationResult, classify};
+use futures::FutureExt;
+use itertools::Itertools;
+use log::{info, warn, debug, trace};
+use reqwest::{Client, RequestBuilder, StatusCode};
+use serde_json::{Value, json};
+use rand::distributions::{Alphanumeric, DistString};
+use crate::{CONFIG, Error, escape_html, cache::{build_cache_key, build_stable_hash, read_cache, write_cache}, config::{AudioProcessingConfig, AzureConfig, ForvoConfig, TtsProviderConfig, audio_format_extension, default_media_name_length}, providers::{check_voice_available, honour_retry_after_except_bad_request, retry_policy}};
+
+static USE_FALLBACK_VOICE: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug)]
+pub struct AudioFile {
+    pub file: PathBuf
+}
+
+impl AudioFile {
+    pub(crate) fn build_note_field(&self) -> String {
+        let end_file = self.file.file_name().unwrap().to_str().unwrap();
+        format!("[sound:{end_file}]")
+    }
+}
+
+pub(crate) fn build_voices_request(client: &Client, azure_config: &AzureConfig) -> RequestBuilder {
+    client.get(azure_config.build_speech_url("cognitiveservices/voices/list"))
+        .header("Ocp-Apim-Subscription-Key", &azure_config.speech.key)
+}
+
+pub async fn get_available_voices(client: &Client, azure_config: &AzureConfig) -> Result<Vec<Value>, Error> { //Voices for the configured locale
+    let res = build_voices_request(client, azure_config)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    let json = res.json::<Value>().await?;
+    trace!("Voices List: {:#?}", json);
+
+    Ok(json.as_array().into_iter().flatten()
+        .filter(|voice| voice["Locale"].as_str() == Some(azure_config.speech.locale.as_str()))
+        .cloned()
+        .collect_vec())
+}
+
+trait TtsProvider {
+    async fn get_tts(&self, text: &str, tempdir: &Path, client: &Client) -> Option<AudioFile>;
+}
+
+pub(crate) struct AzureTtsProvider<'a> {
+    pub(crate) azure_config: &'a AzureConfig,
+    pub(crate) rate: Option<&'a str>, //A prosody rate, e.g. -30% for slower speech
+}
+
+impl AzureTtsProvider<'_> {
+    //The source (which names the audio file), cache key and SSML for the text, shared with the batch synthesis so both find the same audio
+    fn build_request(&self, text: &str, use_fallback_voice: bool) -> (String, String, String) {
+        let azure_config = self.azure_config;
+        let (voice_name, locale) = azure_config.speech.select_voice(use_fallback_voice);
+        let source = match self.rate {
+            Some(rate) => format!("{}/{}/{}", voice_name, azure_config.speech.audio_format, rate),
+            None => format!("{}/{}", voice_name, azure_config.speech.audio_format),
+        };
+        let cache_key = build_cache_key("tts", &[text, &source, locale, azure_config.speech.latin_lang.as_deref().unwrap_or_default()]);
+        let ssml = format!("
+                    <speak version='1.0' xml:lang='{0}'>
+                        <voice xml:lang='{0}' name='{1}'>
+                            {2}
+                        </voice>
+                    </speak>", locale, voice_name, build_prosody(&build_ssml_text(text, azure_config.speech.latin_lang.as_deref()), self.rate));
+        (source, cache_key, ssml)
+    }
+}
+
+impl TtsProvider for AzureTtsProvider<'_> {
+    async fn get_tts(&self, text: &str, tempdir: &Path, client: &Client) -> Option<AudioFile> {
+        let azure_config = self.azure_config;
+        let extension = audio_format_extension(&azure_config.speech.audio_format);
+        let mut row_voice_rejected = false;
+        for attempt in 1..=AZURE_TTS_ATTEMPTS {
+            let use_fallback_voice = azure_config.speech.uses_fallback_voice(USE_FALLBACK_VOICE.load(Ordering::Relaxed), row_voice_rejected);
+            let (source, cache_key, ssml) = self.build_request(text, use_fallback_voice);
+            if let Some(bytes) = read_batch_audio(&cache_key).or_else(|| read_cache(&cache_key)) {
+                return Some(write_audio_file(text, &source, &bytes, extension, tempdir));
+            }
+            let res = retry_policy().retry(||
+                client.post(azure_config.build_speech_url("cognitiveservices/v1"))
+                    .header("Ocp-Apim-Subscription-Key", &azure_config.speech.key)
+                    .header("Content-Type", "application/ssml+xml")
+                    .header("X-Microsoft-OutputFormat", &azure_config.speech.audio_format)
+                    .body(ssml.clone())
+                    .send()
+                    .then(honour_retry_after_except_bad_request)
+                )
+                .await;
+            trace!("Response from TTS: {:#?}", res);
+            let res = match res {
+                Ok(res) => res,
+                Err(error) => {
+                    warn!("Text to speech request for {} failed: {}", text, error);
+                    return None;
+                },
+            };
+
+            //Azure rejects a voice it no longer has (or never had) with a bad request, but also SSML it can't read (e.g. a bad slow_rate or
+            //latin_lang), so the fallback voice is only used once the voice list confirms it's the voice. Anything else only fails this text
+            if res.status() == StatusCode::BAD_REQUEST {
+                let voice_name = azure_config.speech.select_voice(use_fallback_voice).0;
+                if !use_fallback_voice && azure_config.speech.fallback_voice_name.is_some() && is_voice_unavailable(voice_name, client, azure_config).await {
+                    if azure_config.speech.is_row_voice {
+                        warn!("Azure rejected the voice {} for {}, using the fallback voice {} for this row", voice_name, text, azure_config.speech.select_voice(true).0);
+                        row_voice_rejected = true;
+                    } else if !USE_FALLBACK_VOICE.swap(true, Ordering::Relaxed) {
+                        warn!("Azure rejected the voice {}, switching to the fallback voice {} for the rest of the run", voice_name, azure_config.speech.select_voice(true).0);
+                    }
+                    continue;
+                }
+                warn!("Azure rejected the text to speech request for {} with {}: {}", text, voice_name, res.text().await.unwrap_or_default());
+                return None;
+            }
+            let bytes = res.bytes().await.unwrap_or_default();
+            if is_valid_audio(&bytes, extension) {
+                write_cache(&cache_key, &bytes);
+                return Some(write_audio_file(text, &source, &bytes, extension, tempdir));
+            }
+            warn!("Azure returned {} bytes of invalid audio for {} (attempt {} of {})", bytes.len(), text, attempt, AZURE_TTS_ATTEMPTS);
+        }
+        None
+    }
+}
+
+const AZURE_TTS_ATTEMPTS: usize = 3;
+
+async fn is_voice_unavailable(voice_name: &str, client: &Client, azure_config: &AzureConfig) -> bool { //False if the voice list can't be fetched
+    match build_voices_request(client, azure_config).send().await.and_then(|res| res.error_for_status()) {
+        Ok(res) => res.json::<Value>().await.is_ok_and(|voices| check_voice_available(&voices, voice_name).is_err()),
+        Err(error) => {
+            warn!("Unable to fetch the voice list to check {}: {}", voice_name, error);
+            false
+        },
+    }
+}
+
+//Azure's batch synthesis API (https://learn.microsoft.com/azure/ai-services/speech-service/batch-synthesis) makes the audio for many
+//texts in one job, so a large run isn't throttled by a request per text. The audio is kept here until a row's AzureTtsProvider asks for it
+static BATCH_AUDIO: std::sync::Mutex<BTreeMap<String, Vec<u8>>> = std::sync::Mutex::new(BTreeMap::new());
+const BATCH_SYNTHESIS_API_VERSION: &str = "2024-04-01";
+const BATCH_SYNTHESIS_MAX_INPUTS: usize = 1000;
+const BATCH_SYNTHESIS_POLL_SECONDS: u64 = 10;
+const BATCH_SYNTHESIS_MAX_POLLS: usize = 360; //An hour, after which the job's texts are requested one at a time instead
+
+fn read_batch_audio(cache_key: &str) -> Option<Vec<u8>> {
+    BATCH_AUDIO.lock().unwrap().get(cache_key).cloned()
+}
+
+fn build_batch_synthesis_url(region: &str, synthesis_id: &str) -> String {
+    format!("https://{}.api.cognitive.microsoft.com/texttospeech/batchsyntheses/{}?api-version={}", region, synthesis_id, BATCH_SYNTHESIS_API_VERSION)
+}
+
+//Each input is the same SSML a single request would send, so every text keeps its own voice and rate
+fn build_batch_synthesis_body(ssmls: &[String], audio_format: &str) -> Value {
+    json!({
+        "inputKind": "SSML",
+        "inputs": ssmls.iter().map(|ssml| json!({"content": ssml})).collect_vec(),
+        "properties": {
+            "outputFormat": audio_format,
+            "concatenateResult": false,
+            "timeToLiveInHours": 24,
+        },
+    })
+}
+
+//The results zip has a file for each input named by its position from 0001, alongside a summary.json which is skipped
+fn read_batch_synthesis_results(zip_bytes: &[u8]) -> Vec<(usize, Vec<u8>)> {
+    let mut archive = match zip::ZipArchive::new(std::io::Cursor::new(zip_bytes)) {
+        Ok(archive) => archive,
+        Err(error) => {
+            warn!("Unable to read the batch synthesis results: {}", error);
+            return Vec::new();
+        }
+    };
+    (0..archive.len()).filter_map(|index| {
+        let mut file = archive.by_index(index).ok()?;
+        let position = Path::new(file.name()).file_stem()?.to_str()?.parse::<usize>().ok()?.checked_sub(1)?;
+        let mut bytes = Vec::new();
+        std::io::Read::read_to_end(&mut file, &mut bytes).ok()?;
+        Some((position, bytes))
+    }).collect_vec()
+}
+
+async fn request_batch_synthesis(ssmls: &[String], client: &Client, azure_config: &AzureConfig) -> Result<Vec<(usize, Vec<u8>)>, String> {
+    let synthesis_id = format!("gen-mandarin-anki-{}", Alphanumeric.sample_string(&mut rand::thread_rng(), 12).to_lowercase());
+    let url = build_batch_synthesis_url(&azure_config.region, &synthesis_id);
+    client.put(&url)
+        .header("Ocp-Apim-Subscription-Key", &azure_config.speech.key)
+        .json(&build_batch_synthesis_body(ssmls, &azure_config.speech.audio_format))
+        .send()
+        .await.and_then(|res| res.error_for_status())
+        .map_err(|error| error.to_string())?;
+    info!("Submitted {} texts to Azure batch synthesis as {}", ssmls.len(), synthesis_id);
+
+    for _ in 0..BATCH_SYNTHESIS_MAX_POLLS {
+        tokio::time::sleep(Duration::from_secs(BATCH_SYNTHESIS_POLL_SECONDS)).await;
+        let json = client.get(&url)
+            .header("Ocp-Apim-Subscription-Key", &azure_config.speech.key)
+            .send()
+            .await.and_then(|res| res.error_for_status())
+            .map_err(|error| error.to_string())?
+            .json::<Value>().await
+            .map_err(|error| error.to_string())?;
+        trace!("Batch synthesis status: {:#?}", json);
+        match json["status"].as_str() {
+            Some("Succeeded") => {
+                let result_url = json["outputs"]["result"].as_str().ok_or("Azure didn't say where the results are")?;
+                let zip_bytes = client.get(result_url)
+                    .send()
+                    .await.and_then(|res| res.error_for_status())
+                    .map_err(|error| error.to_string())?
+                    .bytes().await
+                    .map_err(|error| error.to_string())?;
+                return Ok(read_batch_synthesis_results(&zip_bytes));
+            },
+            Some("Failed") => return Err(format!("Azure reported {} failed, see trace.log for why", synthesis_id)),
+            _ => debug!("Batch synthesis {} is {}", synthesis_id, json["status"]),
+        }
+    }
+    Err(format!("{} hadn't finished after {} seconds", synthesis_id, BATCH_SYNTHESIS_POLL_SECONDS as usize * BATCH_SYNTHESIS_MAX_POLLS))
+}
+
+//Anything already cached is left out, and any text the batch doesn't produce is requested on its own when its row is built
+pub(crate) async fn synthesise_batch(inputs: &[(String, AzureTtsProvider<'_>)], client: &Client) {
+    let configured_voice_rejected = USE_FALLBACK_VOICE.load(Ordering::Relaxed);
+    let requests = inputs.iter()
+        .map(|(text, azure_tts_provider)| {
+            let use_fallback_voice = azure_tts_provider.azure_config.speech.uses_fallback_voice(configured_voice_rejected, false);
+            (azure_tts_provider.azure_config, azure_tts_provider.build_request(text, use_fallback_voice))
+        })
+        .unique_by(|(_, (_, cache_key, _))| cache_key.clone())
+        .filter(|(_, (_, cache_key, _))| read_cache(cache_key).is_none())
+        .collect_vec();
+    for chunk in requests.chunks(BATCH_SYNTHESIS_MAX_INPUTS) {
+        let azure_config = chunk[0].0; //Only the voice differs between rows, the region, key and format are the same
+        let extension = audio_format_extension(&azure_config.speech.audio_format);
+        let ssmls = chunk.iter().map(|(_, (_, _, ssml))| ssml.clone()).collect_vec();
+        match request_batch_synthesis(&ssmls, client, azure_config).await {
+            Ok(results) => for (position, bytes) in results {
+                match chunk.get(position) {
+                    Some((_, (_, cache_key, _))) if is_valid_audio(&bytes, extension) => {
+                        write_cache(cache_key, &bytes);
+                        BATCH_AUDIO.lock().unwrap().insert(cache_key.clone(), bytes);
+                    },
+                    _ => warn!("Skipping batch synthesis result {} which isn't valid audio for any text", position + 1),
+                }
+            },
+            Err(error) => warn!("Batch synthesis failed, its {} texts will be requested one at a time: {}", chunk.len(), error),
+        }
+    }
+}
+
+//Azure occasionally answers 200 with an empty body, which Anki can't play, so check the bytes look like the requested format
+fn is_valid_audio(bytes: &[u8], extension: &str) -> bool {
+    match extension {
+        "mp3" => bytes.starts_with(b"ID3") || matches!(bytes, [0xFF, second, ..] if second & 0xE0 == 0xE0),
+        "ogg" => bytes.starts_with(b"OggS"),
+        "wav" => bytes.starts_with(b"RIFF"),
+        "webm" => bytes.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]),
+        _ => !bytes.is_empty(),
+    }
+}
+
+fn build_prosody(ssml_text: &str, rate: Option<&str>) -> String { //Only slowed down when a rate is given
+    match rate {
+        Some(rate) => format!("<prosody rate='{}'>{}</prosody>", rate, ssml_text),
+        None => ssml_text.to_string(),
+    }
+}
+
+//Wraps each run of Latin text in a lang element so a mixed sentence isn't read entirely with the Mandarin voice.
+//The text is escaped, since a stray & or < would make the SSML invalid and Azure would reject the whole request
+fn build_ssml_text(text: &str, latin_lang: Option<&str>) -> String {
+    let Some(latin_lang) = latin_lang else {
+        return escape_html(text);
+    };
+    text.chars()
+        .group_by(|char| char.is_ascii())
+        .into_iter()
+        .map(|(is_ascii, chars)| {
+            let span = chars.collect::<String>();
+            let trimmed_span = span.trim();
+            match is_ascii && trimmed_span.contains(|char: char| char.is_ascii_alphabetic()) && classify(trimmed_span) != ClassificationResult::ZH {
+                true => escape_html(&span).replace(&escape_html(trimmed_span), &format!("<lang xml:lang='{}'>{}</lang>", latin_lang, escape_html(trimmed_span))),
+                false => escape_html(&span),
+            }
+        })
+        .join("")
+}
+
+struct ForvoTtsProvider<'a> {
+    forvo_config: &'a ForvoConfig
+}
+
+impl TtsProvider for ForvoTtsProvider<'_> {
+    async fn get_tts(&self, text: &str, tempdir: &Path, client: &Client) -> Option<AudioFile> { //Returns none if Forvo has no human recording of the text
+        let forvo_config = self.forvo_config;
+        let res = client.get(format!("https://apifree.forvo.com/key/{}/format/json/action/word-pronunciations/word/{}/language/{}/order/rate-desc",
+                &forvo_config.key, url_escape::encode_component(text), &forvo_config.language))
+            .send()
+            .await
+            .and_then(|res| res.error_for_status());
+        trace!("Response from Forvo: {:#?}", res);
+        let json = match res {
+            Ok(res) => res.json::<Value>().await.ok()?,
+            Err(error) => {
+                warn!("Error fetching pronunciations from Forvo: {}", error);
+                return None;
+            }
+        };
+        debug!("Json From Forvo: {:#?}", json);
+
+        let mp3_url = json["items"][0]["pathmp3"].as_str()?;
+        let bytes = client.get(mp3_url).send().await.ok()?.error_for_status().ok()?.bytes().await.ok()?;
+
+        Some(write_audio_file(text, "forvo", &bytes, "mp3", tempdir))
+    }
+}
+
+struct SilentTtsProvider;
+
+impl SilentTtsProvider {
+    //A single mpeg 1 layer 3 frame (32kbps, 44.1khz, mono) with all zero side info, which decodes as silence
+    const SILENT_MP3_FRAME_HEADER: [u8; 4] = [0xFF, 0xFB, 0x10, 0xC0];
+    const SILENT_MP3_FRAME_LENGTH: usize = 104;
+    const SILENT_MP3_FRAME_COUNT: usize = 10;
+
+    fn build_silent_mp3() -> Vec<u8> {
+        let mut frame = Self::SILENT_MP3_FRAME_HEADER.to_vec();
+        frame.resize(Self::SILENT_MP3_FRAME_LENGTH, 0);
+        frame.repeat(Self::SILENT_MP3_FRAME_COUNT)
+    }
+}
+
+impl TtsProvider for SilentTtsProvider {
+    async fn get_tts(&self, text: &str, tempdir: &Path, _client: &Client) -> Option<AudioFile> {
+        Some(write_audio_file(text, "silent", &SilentTtsProvider::build_silent_mp3(), "mp3", tempdir))
+    }
+}
+
+//Named from a hash of the text and where the audio came from (e.g. the voice and format), so re-importing the same audio reuses
+//the media already in the collection. A salt is only added if the same audio has already been written during this run
+pub(crate) fn write_audio_file(text: &str, source: &str, bytes: &[u8], extension: &str, tempdir: &Path) -> AudioFile {
+    let media_name_length = CONFIG.get().map(|config| config.media_name_length).unwrap_or_else(default_media_name_length);
+    let file_stem = match build_media_slug(text, media_name_length) {
+        slug if slug.is_empty() => build_audio_hash(text, source),
+        slug => format!("{}-{}", slug, build_audio_hash(text, source)),
+    };
+    let mut file_destination = tempdir.join(format!("{}.{}", file_stem, extension));
+    let mut file = match File::options().write(true).create_new(true).open(&file_destination) {
+        Ok(file) => file,
+        Err(error) if error.kind() == std::io::ErrorKind::AlreadyExists => {
+            let salt = Alphanumeric.sample_string(&mut rand::thread_rng(), 5);
+            file_destination = tempdir.join(format!("{}{}.{}", file_stem, salt, extension));
+            File::create(&file_destination).unwrap()
+        },
+        Err(error) => panic!("Unable to create {}: {}", file_destination.display(), error),
+    };
+    debug!("Audio Temp File: {}", file_destination.display());
+    file.write_all(bytes).unwrap();
+
+    AudioFile {
+        file: file_destination
+    }
+}
+
+//The start of the text, readable in the media folder, with anything that isn't a letter, number or hanzi replaced by a dash
+fn build_media_slug(text: &str, length: usize) -> String {
+    let slug = text.chars()
+        .map(|char| match char.is_alphanumeric() {
+            true => char,
+            false => '-',
+        })
+        .dedup_by(|previous, char| *previous == '-' && *char == '-')
+        .collect::<String>();
+    slug.trim_matches('-').chars().take(length).collect::<String>().trim_end_matches('-').to_string()
+}
+
+fn build_audio_hash(text: &str, source: &str) -> String {
+    format!("{:016x}", build_stable_hash(&[text, source]))
+}
+
+pub async fn get_tts(text: &str, tempdir: PathBuf, client: &Client, azure_config: &AzureConfig) -> Option<AudioFile> { //Returns none if Azure never sent playable audio
+    if CONFIG.get().is_some_and(|config| config.tts_provider == TtsProviderConfig::Silent) {
+        return SilentTtsProvider.get_tts(text, &tempdir, client).await;
+    }
+    let audio_file = get_recorded_tts(text, &tempdir, client, azure_config).await?;
+    if let Some(audio_processing_config) = CONFIG.get().and_then(|config| config.audio_processing.as_ref()) {
+        process_audio_file(&audio_file, audio_processing_config).await;
+    }
+    Some(audio_file)
+}
+
+//Always from Azure, since a Forvo recording can't be slowed down
+pub async fn get_slow_tts(text: &str, tempdir: PathBuf, client: &Client, azure_config: &AzureConfig) -> Option<AudioFile> {
+    if CONFIG.get().is_some_and(|config| config.tts_provider == TtsProviderConfig::Silent) {
+        return SilentTtsProvider.get_tts(text, &tempdir, client).await;
+    }
+    let audio_file = AzureTtsProvider { azure_config, rate: Some(&azure_config.speech.slow_rate) }.get_tts(text, &tempdir, client).await?;
+    if let Some(audio_processing_config) = CONFIG.get().and_then(|config| config.audio_processing.as_ref()) {
+        process_audio_file(&audio_file, audio_processing_config).await;
+    }
+    Some(audio_file)
+}
+
+async fn get_recorded_tts(text: &str, tempdir: &Path, client: &Client, azure_config: &AzureConfig) -> Option<AudioFile> {
+    //Prefer a human recording for short words when Forvo is configured
+    if let Some(forvo_config) = CONFIG.get().and_then(|config| config.forvo.as_ref()) {
+        if text.chars().count() <= forvo_config.max_characters {
+            match (ForvoTtsProvider { forvo_config }).get_tts(text, tempdir, client).await {
+                Some(audio_file) => return Some(audio_file),
+                None => info!("No Forvo pronunciation found for {}, falling back to Azure", text),
+            }
+        }
+    }
+    let audio_file = AzureTtsProvider { azure_config, rate: None }.get_tts(text, tempdir, client).await;
+    if audio_file.is_none() {
+        warn!("No audio could be generated for {}, its note will have no audio", text);
+    }
+    audio_file
+}
+
+//Trims and normalises the file in place, leaving it untouched if ffmpeg fails
+async fn process_audio_file(audio_file: &AudioFile, audio_processing_config: &AudioProcessingConfig) {
+    let extension = audio_file.file.extension().and_then(|extension| extension.to_str()).unwrap_or("mp3");
+    let processed_file = audio_file.file.with_extension(format!("processed.{}", extension));
+    let args = match audio_processing_config.build_args(&audio_file.file, &processed_file) {
+        Some(args) => args,
+        None => return,
+    };
+    let output = tokio::process::Command::new(&audio_processing_config.ffmpeg)
+        .args(&args)
+        .output()
+        .await;
+    match output {
+        Ok(output) if output.status.success() => {
+            debug!("Processed audio file {}", audio_file.file.display());
+            std::fs::rename(&processed_file, &audio_file.file).unwrap();
+        },
+        Ok(output) => {
+            warn!("ffmpeg exited with {} processing {}, keeping the original audio: {}", output.status, audio_file.file.display(), String::from_utf8_lossy(&output.stderr).trim());
+            let _ = std::fs::remove_file(&processed_file);
+        },
+        Err(error) => warn!("Unable to run {} to process {}, keeping the original audio: {}", audio_processing_config.ffmpeg, audio_file.file.display(), error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::{config::parse_config, providers::spawn_mock_server};
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_get_available_voices() {
+        let client = reqwest::Client::new();
+        let voices = get_available_voices(&client, &parse_config(None).unwrap().azure).await.unwrap();
+        println!("Got Voices: {:#?}", voices);
+        assert!(!voices.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_get_tts() {
+        let client = reqwest::Client::new();
+        let tempdir = tempfile::Builder::new().prefix("test_synthesize_text").tempdir().unwrap();
+        let audio_file = get_tts("你好", tempdir.into_path(), &client, &parse_config(None).unwrap().azure).await.unwrap();
+        println!("Created Audio FIle: {:#?}", audio_file);
+        assert!(audio_file.file.exists())
+    }
+
+    #[test]
+    fn test_is_valid_audio() {
+        assert!(!is_valid_audio(&[], "mp3"));
+        assert!(is_valid_audio(&SilentTtsProvider::build_silent_mp3(), "mp3"));
+        assert!(is_valid_audio(b"ID3\x04\x00", "mp3"));
+        assert!(!is_valid_audio(b"<html>error</html>", "mp3"));
+        assert!(is_valid_audio(b"OggS\x00\x02", "ogg"));
+        assert!(!is_valid_audio(&[], "wav"));
+        assert!(is_valid_audio(b"\x00\x01", "raw"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_get_silent_tts() {
+        let client = reqwest::Client::new();
+        let tempdir = tempfile::Builder::new().prefix("test_get_silent_tts").tempdir().unwrap();
+        let audio_file = SilentTtsProvider.get_tts("你好", tempdir.path(), &client).await.unwrap();
+        println!("Created Audio FIle: {:#?}", audio_file);
+        let bytes = std::fs::read(&audio_file.file).unwrap();
+        assert_eq!(bytes[..2], [0xFF, 0xFB]);
+        assert_eq!(bytes.len(), 1040);
+        assert!(audio_file.build_note_field().ends_with(".mp3]"));
+    }
+
+    #[test]
+    fn test_write_audio_file_stable_name() {
+        let tempdir = tempfile::Builder::new().prefix("test_write_audio_file_stable_name").tempdir().unwrap();
+        let other_tempdir = tempfile::Builder::new().prefix("test_write_audio_file_stable_name").tempdir().unwrap();
+        let audio_file = write_audio_file("你好", "zh-TW-YunJheNeural/audio-48khz-192kbitrate-mono-mp3", &[0], "mp3", tempdir.path());
+        assert_eq!(audio_file.file.file_name(), write_audio_file("你好", "zh-TW-YunJheNeural/audio-48khz-192kbitrate-mono-mp3", &[0], "mp3", other_tempdir.path()).file.file_name());
+        assert_ne!(audio_file.file.file_name(), write_audio_file("你好", "zh-TW-HsiaoChenNeural/audio-48khz-192kbitrate-mono-mp3", &[0], "mp3", other_tempdir.path()).file.file_name());
+        let collision = write_audio_file("你好", "zh-TW-YunJheNeural/audio-48khz-192kbitrate-mono-mp3", &[0], "mp3", tempdir.path());
+        assert_ne!(audio_file.file, collision.file);
+        assert!(audio_file.file.exists() && collision.file.exists());
+        //Pinned, since a different name would add the same audio to the collection again
+        assert_eq!(build_audio_hash("你好", "zh-TW-YunJheNeural/audio-48khz-192kbitrate-mono-mp3"), "3ee227501d490d49");
+    }
+
+    #[test]
+    fn test_build_ssml_text() {
+        assert_eq!(build_ssml_text("我用Python寫程式", None), "我用Python寫程式");
+        assert_eq!(build_ssml_text("我用Python寫程式", Some("en-US")), "我用<lang xml:lang='en-US'>Python</lang>寫程式");
+        assert_eq!(build_ssml_text("我們去 happy hour 吧，2023年", Some("en-US")), "我們去 <lang xml:lang='en-US'>happy hour</lang> 吧，2023年");
+        assert_eq!(build_ssml_text("大小<5 & >1", None), "大小&lt;5 &amp; &gt;1");
+        assert_eq!(build_ssml_text("我喜歡Tom & Jerry，3<4", Some("en-US")), "我喜歡<lang xml:lang='en-US'>Tom &amp; Jerry</lang>，3&lt;4");
+    }
+
+    #[test]
+    fn test_build_prosody() {
+        assert_eq!(build_prosody("你好", None), "你好");
+        assert_eq!(build_prosody("你好", Some("-30%")), "<prosody rate='-30%'>你好</prosody>");
+    }
+
+    #[test]
+    fn test_build_media_slug() {
+        assert_eq!(build_media_slug("你好", 10), "你好");
+        assert_eq!(build_media_slug("都是因為媽媽太*寵*他，才會這麼軟弱", 11), "都是因為媽媽太-寵-他");
+        assert_eq!(build_media_slug("我用 Python，寫程式", 20), "我用-Python-寫程式");
+        assert_eq!(build_media_slug("你好，世界", 3), "你好");
+        assert_eq!(build_media_slug("你好", 0), "");
+    }
+
+    //Answers the voice list and text to speech requests like Azure, rejecting SSML for any voice it doesn't synthesise with a bad request
+    fn spawn_mock_speech_server(listed_voices: &[&str], synthesised_voices: &[&str]) -> (String, Arc<std::sync::Mutex<Vec<String>>>) {
+        let voices = json!(listed_voices.iter().map(|voice| json!({"ShortName": voice, "Locale": "zh-TW"})).collect_vec()).to_string();
+        let synthesised_voices = synthesised_voices.iter().map(|voice| voice.to_string()).collect_vec();
+        spawn_mock_server(move |request_line, body| match request_line.contains("/voices/list") {
+            true => ("200 OK", voices.clone().into_bytes()),
+            false if synthesised_voices.iter().any(|voice| body.contains(&format!("name='{}'", voice))) => ("200 OK", [0xFF, 0xFB, 0x90, 0x00].repeat(4)),
+            false => ("400 Bad Request", b"Unsupported voice".to_vec()),
+        })
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_tts_bad_request() {
+        //The voice is still listed, so the bad request is for this text only and the fallback voice isn't used
+        let (endpoint, requests) = spawn_mock_speech_server(&["zh-TW-YunJheNeural", "zh-TW-HsiaoYuNeural"], &["zh-TW-HsiaoYuNeural"]);
+        let azure_config: AzureConfig = serde_json::from_value(json!({
+            "translator": {"key": "translator key"},
+            "speech": {"key": "speech key", "voice_name": "zh-TW-YunJheNeural", "locale": "zh-TW", "fallback_voice_name": "zh-TW-HsiaoYuNeural", "endpoint": endpoint},
+            "region": "uksouth",
+        })).unwrap();
+        let tempdir = tempfile::Builder::new().prefix("test_tts_bad_request").tempdir().unwrap();
+        assert!(AzureTtsProvider { azure_config: &azure_config, rate: None }.get_tts("晚安", tempdir.path(), &reqwest::Client::new()).await.is_none());
+        let requests = requests.lock().unwrap();
+        //Sent once rather than retried, then checked against the voice list
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].starts_with("POST /cognitiveservices/v1") && requests[1].starts_with("GET /cognitiveservices/voices/list"));
+        assert!(!USE_FALLBACK_VOICE.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_row_voice_fallback_request() {
+        //Azure doesn't have the row's misspelled voice, so that row is read by the fallback voice without switching the rest of the run
+        let (endpoint, requests) = spawn_mock_speech_server(&["zh-TW-YunJheNeural", "zh-TW-HsiaoYuNeural"], &["zh-TW-YunJheNeural", "zh-TW-HsiaoYuNeural"]);
+        let azure_config: AzureConfig = serde_json::from_value(json!({
+            "translator": {"key": "translator key"},
+            "speech": {"key": "speech key", "voice_name": "zh-TW-YunJheNeural", "locale": "zh-TW", "fallback_voice_name": "zh-TW-HsiaoYuNeural", "endpoint": endpoint},
+            "region": "uksouth",
+        })).unwrap();
+        let misspelled_row = azure_config.with_voice_name(Some("zh-TW-HsiaoChenNeral"));
+        let tempdir = tempfile::Builder::new().prefix("test_row_voice_fallback_request").tempdir().unwrap();
+        let audio_file = AzureTtsProvider { azure_config: &misspelled_row, rate: None }.get_tts("早安", tempdir.path(), &reqwest::Client::new()).await.unwrap();
+        assert!(audio_file.file.exists());
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[0].starts_with("POST /cognitiveservices/v1") && requests[0].contains("name='zh-TW-HsiaoChenNeral'"));
+        assert!(requests[1].starts_with("GET /cognitiveservices/voices/list"));
+        assert!(requests[2].starts_with("POST /cognitiveservices/v1") && requests[2].contains("name='zh-TW-HsiaoYuNeural'"));
+        assert!(!USE_FALLBACK_VOICE.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_batch_synthesis() {
+        let azure_config: AzureConfig = serde_json::from_value(json!({
+            "translator": {"key": "translator key"},
+            "speech": {"key": "speech key", "voice_name": "zh-TW-YunJheNeural", "locale": "zh-TW"},
+            "region": "uksouth",
+        })).unwrap();
+        assert_eq!(build_batch_synthesis_url("uksouth", "gen-mandarin-anki-abc"), "https://uksouth.api.cognitive.microsoft.com/texttospeech/batchsyntheses/gen-mandarin-anki-abc?api-version=2024-04-01");
+        let slow_provider = AzureTtsProvider { azure_config: &azure_config, rate: Some("-30%") };
+        let (_, slow_cache_key, slow_ssml) = slow_provider.build_request("你好", false);
+        assert!(slow_ssml.contains("<prosody rate='-30%'>你好</prosody>") && slow_ssml.contains("name='zh-TW-YunJheNeural'"));
+        let body = build_batch_synthesis_body(std::slice::from_ref(&slow_ssml), &azure_config.speech.audio_format);
+        assert_eq!(body["inputKind"], "SSML");
+        assert_eq!(body["inputs"][0]["content"], slow_ssml.as_str());
+        assert_eq!(body["properties"]["outputFormat"], azure_config.speech.audio_format.as_str());
+
+        let mut zip_writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        for (name, contents) in [("summary.json", b"{}".to_vec()), ("0002.mp3", vec![2]), ("0001.mp3", vec![1])] {
+            zip_writer.start_file(name, zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored)).unwrap();
+            zip_writer.write_all(&contents).unwrap();
+        }
+        let zip_bytes = zip_writer.finish().unwrap().into_inner();
+        assert_eq!(read_batch_synthesis_results(&zip_bytes).into_iter().sorted().collect_vec(), vec![(0, vec![1]), (1, vec![2])]);
+
+        //Audio from the batch is used without a request, and only for the text, voice and rate it was made for
+        let provider = AzureTtsProvider { azure_config: &azure_config, rate: None };
+        let (_, cache_key, _) = provider.build_request("你好", false);
+        assert_ne!(cache_key, slow_cache_key);
+        BATCH_AUDIO.lock().unwrap().insert(cache_key, SilentTtsProvider::build_silent_mp3());
+        let tempdir = tempfile::Builder::new().prefix("test_batch_synthesis").tempdir().unwrap();
+        let audio_file = provider.get_tts("你好", tempdir.path(), &reqwest::Client::new()).await.unwrap();
+        assert_eq!(std::fs::read(&audio_file.file).unwrap(), SilentTtsProvider::build_silent_mp3());
+    }
+}
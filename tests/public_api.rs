@@ -0,0 +1,8 @@
+use gen_mandarin_anki_rs::{convert_pinyin_to_zhuyin, tokenise_sentence};
+
+#[test]
+fn test_tokenise_sentence_from_library() {
+    let tokens = tokenise_sentence("我喜歡學習中文");
+    assert_eq!(tokens.iter().map(|token| token.text.as_str()).collect::<Vec<_>>(), ["我", "喜歡", "學習", "中文"]);
+    assert_eq!(convert_pinyin_to_zhuyin("nǐ hǎo").unwrap(), "ㄋㄧˇ,ㄏㄠˇ");
+}